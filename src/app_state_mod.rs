@@ -12,12 +12,47 @@ pub struct AppConfig {
     pub path_list_source_folders: CrossPathBuf,
     pub path_list_destination_folders: CrossPathBuf,
     pub path_list_readonly_files: CrossPathBuf,
+    pub path_list_destination_special_files: CrossPathBuf,
     pub path_list_for_download: CrossPathBuf,
     pub path_list_for_trash_files: CrossPathBuf,
     pub path_list_just_downloaded: CrossPathBuf,
     pub path_list_for_trash_folders: CrossPathBuf,
     pub path_list_for_create_folders: CrossPathBuf,
     pub path_list_for_change_time_files: CrossPathBuf,
+    pub path_list_for_case_rename_files: CrossPathBuf,
+    pub path_list_for_case_rename_folders: CrossPathBuf,
+    pub path_list_for_move_folders: CrossPathBuf,
+    pub path_list_scrub_progress: CrossPathBuf,
+    pub path_list_scrub_corrupted: CrossPathBuf,
+}
+
+impl AppConfig {
+    /// All the lists in one folder with the default file names like `list_source_files.csv`.  \
+    ///
+    /// Prefer this constructor to the struct literal. A new list in a later version of the library gets its default name here,
+    /// so the bin project does not break when a field is added.  
+    pub fn new(path_folder: &CrossPathBuf) -> Result<Self> {
+        Ok(AppConfig {
+            path_list_ext_disk_base_path: path_folder.join_relative("list_ext_disk_base_path.csv")?,
+            path_list_source_files: path_folder.join_relative("list_source_files.csv")?,
+            path_list_destination_files: path_folder.join_relative("list_destination_files.csv")?,
+            path_list_source_folders: path_folder.join_relative("list_source_folders.csv")?,
+            path_list_destination_folders: path_folder.join_relative("list_destination_folders.csv")?,
+            path_list_readonly_files: path_folder.join_relative("list_destination_readonly_files.csv")?,
+            path_list_destination_special_files: path_folder.join_relative("list_destination_special_files.csv")?,
+            path_list_for_download: path_folder.join_relative("list_for_download.csv")?,
+            path_list_for_trash_files: path_folder.join_relative("list_for_trash_files.csv")?,
+            path_list_just_downloaded: path_folder.join_relative("list_just_downloaded.csv")?,
+            path_list_for_trash_folders: path_folder.join_relative("list_for_trash_folders.csv")?,
+            path_list_for_create_folders: path_folder.join_relative("list_for_create_folders.csv")?,
+            path_list_for_change_time_files: path_folder.join_relative("list_for_change_time_files.csv")?,
+            path_list_for_case_rename_files: path_folder.join_relative("list_for_case_rename_files.csv")?,
+            path_list_for_case_rename_folders: path_folder.join_relative("list_for_case_rename_folders.csv")?,
+            path_list_for_move_folders: path_folder.join_relative("list_for_move_folders.csv")?,
+            path_list_scrub_progress: path_folder.join_relative("list_scrub_progress.csv")?,
+            path_list_scrub_corrupted: path_folder.join_relative("list_scrub_corrupted.csv")?,
+        })
+    }
 }
 
 /// This trait defines what functions must the bin project implement then the lib project can use them.  \
@@ -1,39 +1,269 @@
 // compare_mod.rs
 
-use crate::error_mod::Result;
-use crate::{utils_mod::println_to_ui_thread, FileTxt};
+use crate::content_hash_cache_mod::{local_modified_and_size, ContentHashCache};
+use crate::disk_identity_mod::{read_disk_identity, verify_disk_identity, DiskIdentity};
+use crate::error_mod::{Error, Result};
+use crate::local_disk_mod::REASON_FOLLOWED_SYMLINK;
+use crate::plan_mod::{verify_plan_header, write_folder_plan_header, write_plan_header};
+use crate::utils_mod::{is_equal_normalized, path_from_line, path_key, println_to_ui_thread};
+use crate::FileTxt;
 use chrono::{DateTime, Utc};
 use crossplatform_path::CrossPathBuf;
-#[allow(unused_imports)]
 use dropbox_content_hasher::DropboxContentHasher;
-use uncased::UncasedStr;
 
-/// Compare list: the lists and produce list_for_download, list_for_trash_files.  
-pub fn compare_files(ui_tx: std::sync::mpsc::Sender<String>, app_config: &'static crate::AppConfig) -> Result<()> {
+/// Options for compare, move and rename.  
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompareOptions {
+    /// Tolerance for the difference of modified datetime. None means it is detected from the destination filesystem.
+    pub timestamp_tolerance: Option<chrono::Duration>,
+    /// Ignore the modified datetime and decide only by size and content_hash, for disks where timestamps are unreliable.
+    pub hash_only: bool,
+    /// Number of threads to calculate the local content_hash. None means the number of CPUs.
+    pub hash_threads: Option<usize>,
+}
+
+impl CompareOptions {
+    /// Returns the configured timestamp tolerance or the tolerance of the destination filesystem.  \
+    ///
+    /// The tolerance of the filesystem is detected only once and then read from `0_backup_temp`.  
+    pub fn timestamp_tolerance(&self, ext_disk_base_path: &CrossPathBuf) -> Result<chrono::Duration> {
+        if let Some(timestamp_tolerance) = self.timestamp_tolerance {
+            return Ok(timestamp_tolerance);
+        }
+        filesystem_timestamp_tolerance(ext_disk_base_path)
+    }
+
+    /// Like timestamp_tolerance, but it never writes to the disk. It is used by the dry-run.  \
+    ///
+    /// If the tolerance was never detected on this disk, it is 2 seconds like on exFAT and FAT32.  
+    pub fn timestamp_tolerance_read_only(&self, ext_disk_base_path: &CrossPathBuf) -> Result<chrono::Duration> {
+        if let Some(timestamp_tolerance) = self.timestamp_tolerance {
+            return Ok(timestamp_tolerance);
+        }
+        Ok(read_timestamp_tolerance(ext_disk_base_path)?.unwrap_or(chrono::Duration::seconds(2)))
+    }
+}
+
+/// File name of the detected timestamp tolerance in seconds, inside `0_backup_temp`.
+const TIMESTAMP_TOLERANCE_FILE_NAME: &str = "timestamp_tolerance.txt";
+/// File name of the local paths known to compare, inside `0_backup_temp`. It is used to recognize a missing local copy.
+const COMPARED_LOCAL_PATHS_FILE_NAME: &str = "compared_local_paths.csv";
+
+// Reason codes explain why a line is in the plan lists.
+// The reason, the old value and the new value are appended as the last 3 columns of the line.
+/// The file is not in the local backup and it was not there at the last compare. Mostly a new remote file.
+pub const REASON_NEW: &str = "new";
+/// The file was in the local backup at the last compare, but now the local copy is missing. The old value is empty.
+pub const REASON_MISSING: &str = "missing";
+/// The size is different. Old and new value are the sizes.
+pub const REASON_SIZE_CHANGED: &str = "size_changed";
+/// The modified is different and the content_hash is different. Old and new value are the content_hashes.
+pub const REASON_CONTENT_CHANGED: &str = "content_changed";
+/// The modified is different, but the content_hash is equal. Old and new value are the modified datetimes.
+pub const REASON_MODIFIED_CHANGED: &str = "modified_changed";
+/// The local file is not in the source anymore. The old value is the size.
+pub const REASON_NOT_IN_SOURCE: &str = "not_in_source";
+/// The local file is silently corrupted, found by scrub. Old and new value are the content_hashes.
+pub const REASON_CORRUPTED: &str = "corrupted";
+
+/// The column of the reason in list_for_download and list_for_change_time_files.
+pub(crate) const REASON_COLUMN_FOR_DOWNLOAD: usize = 4;
+/// The column of the reason in list_for_trash_files.
+pub(crate) const REASON_COLUMN_FOR_TRASH: usize = 3;
+
+/// Append the reason, old value and new value to the line.  
+pub(crate) fn line_with_reason(line: &str, reason: &str, old_value: &str, new_value: &str) -> String {
+    format!("{line}\t{reason}\t{old_value}\t{new_value}")
+}
+
+/// The source line is not in the destination. It is missing, if it was in the local backup at the last compare.  
+fn line_for_download_new_or_missing(set_compared_local_paths: &std::collections::HashSet<String>, line_source: &str) -> String {
+    let size = line_source.split("\t").nth(2).unwrap_or_default();
+    let reason = if set_compared_local_paths.contains(&path_key(path_from_line(line_source))) {
+        REASON_MISSING
+    } else {
+        REASON_NEW
+    };
+    line_with_reason(line_source, reason, "", size)
+}
+
+/// Read the path keys of the local files known to the last compare. The set is empty before the first compare on this disk.  
+pub(crate) fn read_compared_local_paths(ext_disk_base_path: &CrossPathBuf) -> Result<std::collections::HashSet<String>> {
+    let path_compared_local_paths = ext_disk_base_path
+        .join_relative("0_backup_temp")?
+        .join_relative(COMPARED_LOCAL_PATHS_FILE_NAME)?;
+    if !path_compared_local_paths.exists() {
+        return Ok(std::collections::HashSet::new());
+    }
+    Ok(path_compared_local_paths.read_to_string()?.lines().map(path_key).collect())
+}
+
+/// Write the local paths known to this compare: the local files and the files still missing from the last compare.  \
+///
+/// A missing file stays missing in the next compare, until it is downloaded again or removed from the remote.  \
+/// The file is replaced atomically with a temp file and rename.  
+fn write_compared_local_paths(
+    ext_disk_base_path: &CrossPathBuf,
+    vec_list_destination_files: &[&str],
+    vec_for_download: &[String],
+) -> Result<()> {
+    let vec_paths: Vec<&str> = vec_list_destination_files
+        .iter()
+        .map(|line| path_from_line(line))
+        .chain(
+            vec_for_download
+                .iter()
+                .filter(|line| line.split("\t").nth(REASON_COLUMN_FOR_DOWNLOAD) == Some(REASON_MISSING))
+                .map(|line| path_from_line(line)),
+        )
+        .collect();
+    let path_compared_local_paths = ext_disk_base_path
+        .join_relative("0_backup_temp")?
+        .join_relative(COMPARED_LOCAL_PATHS_FILE_NAME)?;
+    path_compared_local_paths.create_dir_all_for_file()?;
+    let mut path_temp = path_compared_local_paths.to_path_buf_current_os().into_os_string();
+    path_temp.push(".tmp");
+    std::fs::write(&path_temp, vec_paths.join("\n"))?;
+    std::fs::rename(&path_temp, path_compared_local_paths.to_path_buf_current_os())?;
+    Ok(())
+}
+
+/// The destination line is not in the source.  
+fn line_for_trash_with_reason(line_destination: &str) -> String {
+    let size = line_destination.split("\t").nth(2).unwrap_or_default();
+    line_with_reason(line_destination, REASON_NOT_IN_SOURCE, size, "")
+}
+
+/// Count the lines by reason and return a short text like `new: 3, size_changed: 1`.  
+pub(crate) fn count_by_reason(vec_lines: &[&str], reason_column: usize) -> String {
+    let mut count_by_reason: std::collections::BTreeMap<&str, usize> = std::collections::BTreeMap::new();
+    for line in vec_lines.iter() {
+        let reason = line.split("\t").nth(reason_column).unwrap_or("unknown");
+        *count_by_reason.entry(reason).or_default() += 1;
+    }
+    count_by_reason
+        .iter()
+        .map(|(reason, count)| format!("{reason}: {count}"))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Detect the resolution of the modified datetime on the destination filesystem.  \
+///
+/// It writes a probe file, sets the modified with odd seconds and nanoseconds and reads it back.  \
+/// NTFS and ext4 store it exactly, HFS+ rounds to 1 second, exFAT and FAT32 round to 2 seconds.  \
+/// The tolerance is the rounding difference rounded up to whole seconds, but minimum 1 second,
+/// because the remote list has only whole seconds.  \
+/// If the filesystem rejects the modified datetime, the tolerance is 2 seconds like before.  \
+/// The result is saved in `0_backup_temp/timestamp_tolerance.txt`, so the next steps don't write the probe again.
+/// Call it again only if the backup was moved to another filesystem.  
+pub fn detect_timestamp_tolerance(ext_disk_base_path: &CrossPathBuf) -> Result<chrono::Duration> {
+    let path_probe = ext_disk_base_path
+        .join_relative("0_backup_temp")?
+        .join_relative("timestamp_probe.tmp")?;
+    path_probe.create_dir_all_for_file()?;
+    std::fs::write(path_probe.to_path_buf_current_os(), "timestamp probe")?;
+    // 2025-01-01T00:00:01.123456789Z
+    let probe_modified = filetime::FileTime::from_unix_time(1_735_689_601, 123_456_789);
+    let result_set = filetime::set_file_mtime(path_probe.to_path_buf_current_os(), probe_modified);
+    let tolerance = match result_set {
+        Ok(()) => {
+            let read_modified = filetime::FileTime::from_last_modification_time(&std::fs::metadata(path_probe.to_path_buf_current_os())?);
+            let difference_nanos = (i128::from(read_modified.unix_seconds()) * 1_000_000_000 + i128::from(read_modified.nanoseconds()))
+                - (i128::from(probe_modified.unix_seconds()) * 1_000_000_000 + i128::from(probe_modified.nanoseconds()));
+            let difference_seconds = difference_nanos.unsigned_abs().div_ceil(1_000_000_000) as i64;
+            chrono::Duration::seconds(difference_seconds.max(1))
+        }
+        Err(_err) => chrono::Duration::seconds(2),
+    };
+    std::fs::remove_file(path_probe.to_path_buf_current_os())?;
+    let path_tolerance = ext_disk_base_path
+        .join_relative("0_backup_temp")?
+        .join_relative(TIMESTAMP_TOLERANCE_FILE_NAME)?;
+    std::fs::write(path_tolerance.to_path_buf_current_os(), tolerance.num_seconds().to_string())?;
+    Ok(tolerance)
+}
+
+/// The timestamp tolerance of the destination filesystem. It is detected only once and then read from `0_backup_temp`.  
+pub(crate) fn filesystem_timestamp_tolerance(ext_disk_base_path: &CrossPathBuf) -> Result<chrono::Duration> {
+    match read_timestamp_tolerance(ext_disk_base_path)? {
+        Some(timestamp_tolerance) => Ok(timestamp_tolerance),
+        None => detect_timestamp_tolerance(ext_disk_base_path),
+    }
+}
+
+/// The modified datetimes from the lists are equal within the timestamp tolerance, like in compare.  
+pub(crate) fn is_modified_within_tolerance(modified_1: &str, modified_2: &str, timestamp_tolerance: chrono::Duration) -> Result<bool> {
+    let modified_1: DateTime<Utc> = DateTime::parse_from_rfc3339(modified_1)?.into();
+    let modified_2: DateTime<Utc> = DateTime::parse_from_rfc3339(modified_2)?.into();
+    Ok((modified_1 - modified_2).abs() <= timestamp_tolerance)
+}
+
+/// Read the timestamp tolerance saved by detect_timestamp_tolerance. Returns None if it was never detected on this disk.  
+pub(crate) fn read_timestamp_tolerance(ext_disk_base_path: &CrossPathBuf) -> Result<Option<chrono::Duration>> {
+    let path_tolerance = ext_disk_base_path
+        .join_relative("0_backup_temp")?
+        .join_relative(TIMESTAMP_TOLERANCE_FILE_NAME)?;
+    if !path_tolerance.exists() {
+        return Ok(None);
+    }
+    let seconds = path_tolerance
+        .read_to_string()?
+        .trim()
+        .parse::<i64>()
+        .map_err(|_err| Error::ErrorFromString(format!("{TIMESTAMP_TOLERANCE_FILE_NAME} must contain whole seconds")))?;
+    Ok(Some(chrono::Duration::seconds(seconds)))
+}
+
+/// Compare list: the lists and produce list_for_download, list_for_trash_files, list_for_change_time_files, list_for_case_rename_files and list_for_move_folders.  \
+///
+/// The account_id (from remote_account_id) and the include roots must be the same as in the disk identity,
+/// so a wrong disk or an empty mount point is never trashed or filled with a full download.  
+pub fn compare_files(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    app_config: &'static crate::AppConfig,
+    compare_options: &CompareOptions,
+    account_id: &str,
+    include_roots: &[String],
+) -> Result<()> {
     //add_just_downloaded_to_list_local(app_config);
     let base_path = FileTxt::open_for_read(&app_config.path_list_ext_disk_base_path)?.read_to_string()?;
     let base_path = CrossPathBuf::new(&base_path)?;
+    let disk_identity = verify_disk_identity(&base_path, account_id, include_roots)?;
     compare_lists_internal(
         ui_tx,
         &base_path,
+        &disk_identity,
+        compare_options,
         &app_config.path_list_source_files,
         &app_config.path_list_destination_files,
         &app_config.path_list_for_download,
         &app_config.path_list_for_trash_files,
         &app_config.path_list_for_change_time_files,
+        &app_config.path_list_for_case_rename_files,
+        &app_config.path_list_for_move_folders,
+        &app_config.path_list_destination_special_files,
     )?;
     Ok(())
 }
 
-/// Compare list: the lists must be already sorted for this to work correctly.  
+/// Compare list: the lists must be already sorted for this to work correctly.  \
+///
+/// Every plan list is replaced atomically, so running compare twice gives the same lists.  
+#[allow(clippy::too_many_arguments)]
 fn compare_lists_internal(
     ui_tx: std::sync::mpsc::Sender<String>,
     base_path: &CrossPathBuf,
+    disk_identity: &DiskIdentity,
+    compare_options: &CompareOptions,
     path_list_source_files: &CrossPathBuf,
     path_list_destination_files: &CrossPathBuf,
     path_list_for_download: &CrossPathBuf,
     path_list_for_trash: &CrossPathBuf,
     path_list_for_change_time_files: &CrossPathBuf,
+    path_list_for_case_rename_files: &CrossPathBuf,
+    path_list_for_move_folders: &CrossPathBuf,
+    path_list_destination_special_files: &CrossPathBuf,
 ) -> Result<()> {
     let file_list_source_files = FileTxt::open_for_read(path_list_source_files)?;
     let string_list_source_files = file_list_source_files.read_to_string()?;
@@ -51,15 +281,169 @@ fn compare_lists_internal(
         format!("{}: {}", file_list_destination_files.file_name()?, vec_list_destination_files.len()),
     );
 
+    let timestamp_tolerance = compare_options.timestamp_tolerance(base_path)?;
+    println_to_ui_thread(
+        &ui_tx,
+        format!("timestamp tolerance in seconds: {}", timestamp_tolerance.num_seconds()),
+    );
+    if compare_options.hash_only {
+        println_to_ui_thread(&ui_tx, "hash-only mode ignores the modified datetime".to_string());
+    }
+    // the content_hash of local files is calculated only if it is not in the cache
+    let mut content_hash_cache = ContentHashCache::load(base_path)?;
+    // the local files of the last compare, to recognize a missing local copy
+    let set_compared_local_paths = read_compared_local_paths(base_path)?;
+
+    let compared_files = compare_files_in_memory(
+        &ui_tx,
+        base_path,
+        compare_options,
+        timestamp_tolerance,
+        &mut content_hash_cache,
+        &set_compared_local_paths,
+        &vec_list_source_files,
+        &vec_list_destination_files,
+    )?;
+    let vec_for_download = compared_files.vec_for_download;
+    let vec_for_trash = compared_files.vec_for_trash;
+    let vec_for_change_time_files = compared_files.vec_for_change_time_files;
+    let vec_for_case_rename_files = compared_files.vec_for_case_rename_files;
+    let vec_for_move_folders = compared_files.vec_for_move_folders;
+    // the lines for case rename and move folders have the old and the new path
+    let vec_changed_paths: Vec<&str> = vec_for_download
+        .iter()
+        .chain(vec_for_trash.iter())
+        .chain(vec_for_change_time_files.iter())
+        .map(|line| path_from_line(line))
+        .chain(
+            vec_for_case_rename_files
+                .iter()
+                .chain(vec_for_move_folders.iter())
+                .flat_map(|line| line.split("\t").take(2)),
+        )
+        .collect();
+    refuse_changes_under_followed_symlinks(path_list_destination_special_files, &vec_changed_paths)?;
+
+    // the cache keeps only the files that exist in the local backup
+    let set_destination_paths: std::collections::HashSet<&str> = vec_list_destination_files
+        .iter()
+        .map(|line| line.split("\t").next().unwrap_or_default())
+        .collect();
+    content_hash_cache.retain_paths(&set_destination_paths);
+    content_hash_cache.save()?;
+
+    let mut file_list_for_move_folders = FileTxt::open_for_read_and_write(path_list_for_move_folders)?;
+    println_to_ui_thread(
+        &ui_tx,
+        format!("{}: {}", file_list_for_move_folders.file_name()?, vec_for_move_folders.len()),
+    );
+    let string_for_move_folders = vec_for_move_folders.join("\n");
+    file_list_for_move_folders.replace_atomic(&string_for_move_folders)?;
+
+    let mut file_list_for_case_rename_files = FileTxt::open_for_read_and_write(path_list_for_case_rename_files)?;
+    println_to_ui_thread(
+        &ui_tx,
+        format!(
+            "{}: {}",
+            file_list_for_case_rename_files.file_name()?,
+            vec_for_case_rename_files.len()
+        ),
+    );
+    let string_for_case_rename_files = vec_for_case_rename_files.join("\n");
+    file_list_for_case_rename_files.replace_atomic(&string_for_case_rename_files)?;
+
+    let mut file_list_for_change_time_files = FileTxt::open_for_read_and_write(path_list_for_change_time_files)?;
+    println_to_ui_thread(
+        &ui_tx,
+        format!(
+            "{}: {}",
+            file_list_for_change_time_files.file_name()?,
+            vec_for_change_time_files.len()
+        ),
+    );
+    let string_for_change_time_files = vec_for_change_time_files.join("\n");
+    file_list_for_change_time_files.replace_atomic(&string_for_change_time_files)?;
+
+    let mut file_list_for_trash_files = FileTxt::open_for_read_and_write(path_list_for_trash)?;
+    println_to_ui_thread(
+        &ui_tx,
+        format!("{}: {}", file_list_for_trash_files.file_name()?, vec_for_trash.len()),
+    );
+    let string_for_trash_files = vec_for_trash.join("\n");
+    file_list_for_trash_files.replace_atomic(&string_for_trash_files)?;
+    let vec_for_trash_str: Vec<&str> = vec_for_trash.iter().map(|line| line.as_str()).collect();
+    println_to_ui_thread(
+        &ui_tx,
+        format!(
+            "for trash by reason: {}",
+            count_by_reason(&vec_for_trash_str, REASON_COLUMN_FOR_TRASH)
+        ),
+    );
+
+    let mut file_list_for_downloads = FileTxt::open_for_read_and_write(path_list_for_download)?;
+    println_to_ui_thread(
+        &ui_tx,
+        format!("{}: {}", file_list_for_downloads.file_name()?, vec_for_download.len()),
+    );
+    let string_for_download = vec_for_download.join("\n");
+    file_list_for_downloads.replace_atomic(&string_for_download)?;
+    let vec_for_download_str: Vec<&str> = vec_for_download.iter().map(|line| line.as_str()).collect();
+    println_to_ui_thread(
+        &ui_tx,
+        format!(
+            "for download by reason: {}",
+            count_by_reason(&vec_for_download_str, REASON_COLUMN_FOR_DOWNLOAD)
+        ),
+    );
+
+    write_compared_local_paths(base_path, &vec_list_destination_files, &vec_for_download)?;
+    // the plan header is written last, the later steps will refuse to run on a plan from other lists or for another disk
+    write_plan_header(
+        path_list_for_download,
+        &disk_identity.uuid,
+        &[
+            (path_list_source_files, &string_list_source_files),
+            (path_list_destination_files, &string_list_destination_files),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Lists of source lines for download and change time and destination lines for trash.  
+pub(crate) struct ComparedFiles {
+    pub vec_for_download: Vec<String>,
+    pub vec_for_trash: Vec<String>,
+    pub vec_for_change_time_files: Vec<String>,
+    pub vec_for_case_rename_files: Vec<String>,
+    pub vec_for_move_folders: Vec<String>,
+}
+
+/// Compare the sorted lists in memory. It does not write anything to disk.  \
+///
+/// The merge-compare is single-threaded and collects the files that need the local content_hash.  \
+/// Then the content_hash of these local files is calculated in parallel, only if it is not in the cache.  
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn compare_files_in_memory(
+    ui_tx: &std::sync::mpsc::Sender<String>,
+    base_path: &CrossPathBuf,
+    compare_options: &CompareOptions,
+    timestamp_tolerance: chrono::Duration,
+    content_hash_cache: &mut ContentHashCache,
+    set_compared_local_paths: &std::collections::HashSet<String>,
+    vec_list_source_files: &[&str],
+    vec_list_destination_files: &[&str],
+) -> Result<ComparedFiles> {
     let mut vec_for_download: Vec<String> = vec![];
     let mut vec_for_trash: Vec<String> = vec![];
     let mut vec_for_change_time_files: Vec<String> = vec![];
+    let mut vec_for_case_rename_files: Vec<String> = vec![];
     let mut cursor_source = 0;
     let mut cursor_destination = 0;
     //avoid making new allocations or shadowing inside a loop
     let mut vec_line_destination: Vec<&str> = vec![];
     let mut vec_line_source: Vec<&str> = vec![];
-    let mut i = 0;
+    // pairs of source and destination index, that need the content_hash of the local file
+    let mut vec_hash_candidates: Vec<(usize, usize)> = vec![];
     loop {
         vec_line_destination.truncate(3);
         vec_line_source.truncate(3);
@@ -68,127 +452,514 @@ fn compare_lists_internal(
             break;
         } else if cursor_source >= vec_list_source_files.len() {
             // final lines
-            vec_for_trash.push(vec_list_destination_files[cursor_destination].to_string());
+            vec_for_trash.push(line_for_trash_with_reason(vec_list_destination_files[cursor_destination]));
             cursor_destination += 1;
         } else if cursor_destination >= vec_list_destination_files.len() {
             // final lines
-            vec_for_download.push(vec_list_source_files[cursor_source].to_string());
+            vec_for_download.push(line_for_download_new_or_missing(
+                set_compared_local_paths,
+                vec_list_source_files[cursor_source],
+            ));
             cursor_source += 1;
         } else {
             //compare the 2 lines
             // /Video_Backup/DVDs/BikeManual/om/FOXHelp/jap/float_x.htm	2007-01-08T19:31:44Z	45889   content_hash
             vec_line_source = vec_list_source_files[cursor_source].split("\t").collect();
             vec_line_destination = vec_list_destination_files[cursor_destination].split("\t").collect();
-            // UncasedStr preserves the case in the string, but comparison is done case insensitive
-            let path_source: &UncasedStr = vec_line_source[0].into();
-            let path_destination: &UncasedStr = vec_line_destination[0].into();
+            // the path key is lowercase and unicode normalized, but the original path is used on disk
+            let path_source = path_key(vec_line_source[0]);
+            let path_destination = path_key(vec_line_destination[0]);
 
-            if path_source.lt(path_destination) {
-                vec_for_download.push(vec_list_source_files[cursor_source].to_string());
+            if path_source < path_destination {
+                vec_for_download.push(line_for_download_new_or_missing(
+                    set_compared_local_paths,
+                    vec_list_source_files[cursor_source],
+                ));
                 cursor_source += 1;
-            } else if path_source.gt(path_destination) {
-                vec_for_trash.push(vec_list_destination_files[cursor_destination].to_string());
+            } else if path_source > path_destination {
+                vec_for_trash.push(line_for_trash_with_reason(vec_list_destination_files[cursor_destination]));
                 cursor_destination += 1;
-            } else if vec_line_source[2] != vec_line_destination[2] {
-                // equal names, different size
-                vec_for_download.push(vec_list_source_files[cursor_source].to_string());
-                cursor_destination += 1;
-                cursor_source += 1;
             } else {
-                // equal names, equal size, check date and later check content_hash
-                let source_modified_dt_utc: DateTime<Utc> = DateTime::parse_from_rfc3339(vec_line_source[1])?.into();
-                let destination_modified_dt_utc: DateTime<Utc> = DateTime::parse_from_rfc3339(vec_line_destination[1])?.into();
-                // if date is more than 2 seconds different
-                // incredible, incredible, incredible. exFAT is a Microsoft disk format for external disks. It allows for 10ms resolution for LastWrite/modified datetime.
-                // But Microsoft in Win10 driver for exFAT uses only 2seconds resolution. Crazy! After 20 years of existence.
-                // this means that if the time difference is less then 2 seconds, they are probably the same file
-                if chrono::Duration::from(source_modified_dt_utc - destination_modified_dt_utc).abs() > chrono::Duration::seconds(2) {
-                    // 2025-09-21 another strange behavior: for some git object files the modified date is different on my local disk and on Dropbox
-                    // I don't know why is that, but I have 5000 of these files small and large. I suppose the content is equal therefor Dropbox does not sync them.
-                    // /BestiaDev/github_backup_active/github_backup_private/obsidian_bestia_dev/.git/objects/1f/55b1a1662d4c06e1909f73877513bf38cc390e
-                    // I can recognize them id the path contains '/.git/'
-                    // I will use content_hash to be sure that these files are equal.
-                    let path_global_to_destination_file = base_path.join_relative(vec_line_destination[0])?;
-                    // get content_hash from destination file
-                    let local_content_hash = format!(
-                        "{:x}",
-                        DropboxContentHasher::hash_file(path_global_to_destination_file.to_path_buf_current_os())?
-                    );
-
-                    // a simple rudimentary progress bar
-                    if i > 40 {
-                        i = 0;
-                        print!(".");
-                        // flush
-                        use std::io::Write;
-                        std::io::stdout().flush()?;
-                    }
-                    i += 1;
-
-                    if local_content_hash != vec_line_source[3] {
-                        // println!("{} {}", local_content_hash, vec_line_source[3]);
-                        vec_for_download.push(vec_list_source_files[cursor_source].to_string());
+                // equal names, but the case of the file name can be different
+                // the case of the parent folders is renamed with the folders
+                if is_case_only_rename(vec_line_destination[0], vec_line_source[0]) {
+                    vec_for_case_rename_files.push(format!("{}\t{}", vec_line_destination[0], vec_line_source[0]));
+                }
+                if vec_line_source[2] != vec_line_destination[2] {
+                    // equal names, different size
+                    vec_for_download.push(line_with_reason(
+                        vec_list_source_files[cursor_source],
+                        REASON_SIZE_CHANGED,
+                        vec_line_destination[2],
+                        vec_line_source[2],
+                    ));
+                } else {
+                    // equal names, equal size, check date and later check content_hash
+                    // in hash-only mode the date is ignored and the content_hash is always checked
+                    let is_check_content_hash = if compare_options.hash_only {
+                        true
                     } else {
-                        // these files need change datetime
-                        vec_for_change_time_files.push(vec_list_source_files[cursor_source].to_string());
+                        let source_modified_dt_utc: DateTime<Utc> = DateTime::parse_from_rfc3339(vec_line_source[1])?.into();
+                        let destination_modified_dt_utc: DateTime<Utc> = DateTime::parse_from_rfc3339(vec_line_destination[1])?.into();
+                        // if date is more different than the tolerance
+                        // incredible, incredible, incredible. exFAT is a Microsoft disk format for external disks. It allows for 10ms resolution for LastWrite/modified datetime.
+                        // But Microsoft in Win10 driver for exFAT uses only 2seconds resolution. Crazy! After 20 years of existence.
+                        // this means that if the time difference is less then 2 seconds, they are probably the same file
+                        // The tolerance is detected from the destination filesystem or it is configured.
+                        chrono::Duration::from(source_modified_dt_utc - destination_modified_dt_utc).abs() > timestamp_tolerance
+                    };
+                    if is_check_content_hash {
+                        // 2025-09-21 another strange behavior: for some git object files the modified date is different on my local disk and on Dropbox
+                        // I don't know why is that, but I have 5000 of these files small and large. I suppose the content is equal therefor Dropbox does not sync them.
+                        // /BestiaDev/github_backup_active/github_backup_private/obsidian_bestia_dev/.git/objects/1f/55b1a1662d4c06e1909f73877513bf38cc390e
+                        // I can recognize them id the path contains '/.git/'
+                        // I will use content_hash to be sure that these files are equal.
+                        // The candidates are collected here and hashed later in parallel.
+                        vec_hash_candidates.push((cursor_source, cursor_destination));
                     }
+                    // else the metadata is the same, no action
                 }
-                // else the metadata is the same, no action
                 cursor_destination += 1;
                 cursor_source += 1;
             }
         }
     }
-    println!();
 
-    let mut file_list_for_change_time_files = FileTxt::open_for_read_and_write(path_list_for_change_time_files)?;
+    // get content_hash from destination file or from cache
+    let vec_paths_for_hash: Vec<&str> = vec_hash_candidates
+        .iter()
+        .map(|(_index_source, index_destination)| path_from_line(vec_list_destination_files[*index_destination]))
+        .collect();
+    let vec_local_content_hash = content_hash_parallel(
+        ui_tx,
+        base_path,
+        compare_options.hash_threads,
+        content_hash_cache,
+        &vec_paths_for_hash,
+    )?;
+    for ((index_source, index_destination), local_content_hash) in vec_hash_candidates.iter().zip(vec_local_content_hash.iter()) {
+        let vec_line_source: Vec<&str> = vec_list_source_files[*index_source].split("\t").collect();
+        let vec_line_destination: Vec<&str> = vec_list_destination_files[*index_destination].split("\t").collect();
+        if *local_content_hash != vec_line_source[3] {
+            vec_for_download.push(line_with_reason(
+                vec_list_source_files[*index_source],
+                REASON_CONTENT_CHANGED,
+                local_content_hash,
+                vec_line_source[3],
+            ));
+        } else if !compare_options.hash_only {
+            // these files need change datetime
+            vec_for_change_time_files.push(line_with_reason(
+                vec_list_source_files[*index_source],
+                REASON_MODIFIED_CHANGED,
+                vec_line_destination[1],
+                vec_line_source[1],
+            ));
+        }
+    }
+    if !vec_hash_candidates.is_empty() {
+        // back to the same order as the merge-compare, stable sort keeps it deterministic
+        vec_for_download.sort_by_cached_key(|line| path_key(path_from_line(line)));
+    }
+
+    let vec_for_move_folders = detect_folder_moves(
+        base_path,
+        content_hash_cache,
+        vec_list_source_files,
+        vec_list_destination_files,
+        &mut vec_for_download,
+        &mut vec_for_trash,
+    )?;
+
+    Ok(ComparedFiles {
+        vec_for_download,
+        vec_for_trash,
+        vec_for_change_time_files,
+        vec_for_case_rename_files,
+        vec_for_move_folders,
+    })
+}
+
+/// The content_hash of one file. For a new content_hash also the modified and size, to insert into the cache.  
+type ContentHashResult = Result<(String, Option<(String, u64)>)>;
+
+/// Calculate the content_hash of local files in parallel on a rayon pool.  \
+///
+/// The cache is only read in parallel and the new content_hashes are inserted afterwards.  \
+/// The throughput is sent to the ui thread every second. The result is in the same order as the paths.  
+fn content_hash_parallel(
+    ui_tx: &std::sync::mpsc::Sender<String>,
+    base_path: &CrossPathBuf,
+    hash_threads: Option<usize>,
+    content_hash_cache: &mut ContentHashCache,
+    vec_paths: &[&str],
+) -> Result<Vec<String>> {
+    if vec_paths.is_empty() {
+        return Ok(vec![]);
+    }
+    use rayon::prelude::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    // 0 threads means the rayon default: the number of CPUs
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(hash_threads.unwrap_or(0)).build()?;
     println_to_ui_thread(
-        &ui_tx,
+        ui_tx,
         format!(
-            "{}: {}",
-            file_list_for_change_time_files.file_name()?,
-            vec_for_change_time_files.len()
+            "content_hash candidates: {} on {} threads",
+            vec_paths.len(),
+            pool.current_num_threads()
         ),
     );
-    let string_for_change_time_files = vec_for_change_time_files.join("\n");
-    file_list_for_change_time_files.write_append_str(&string_for_change_time_files)?;
+    let hash_start = std::time::Instant::now();
+    let bytes_hashed = AtomicU64::new(0);
+    let last_report_ms = AtomicU64::new(0);
+    let content_hash_cache_ref = &*content_hash_cache;
+    // the new content_hash has also the modified and size, to insert into the cache
+    let vec_results: Vec<ContentHashResult> = pool.install(|| {
+        vec_paths
+            .par_iter()
+            .map_with(ui_tx.clone(), |ui_tx, string_path| -> ContentHashResult {
+                let path_global = base_path.join_relative(string_path)?;
+                let (modified, size) = local_modified_and_size(&path_global)?;
+                if let Some(content_hash) = content_hash_cache_ref.get_valid(string_path, &modified, size) {
+                    return Ok((content_hash.to_string(), None));
+                }
+                let content_hash = format!("{:x}", DropboxContentHasher::hash_file(path_global.to_path_buf_current_os())?);
+                let bytes_total = bytes_hashed.fetch_add(size, Ordering::Relaxed) + size;
+                // Every 1 second send the throughput, only one thread wins the compare_exchange
+                let elapsed_ms = hash_start.elapsed().as_millis() as u64;
+                let last_ms = last_report_ms.load(Ordering::Relaxed);
+                if elapsed_ms >= last_ms + 1000
+                    && last_report_ms
+                        .compare_exchange(last_ms, elapsed_ms, Ordering::Relaxed, Ordering::Relaxed)
+                        .is_ok()
+                {
+                    println_to_ui_thread(
+                        ui_tx,
+                        format!(
+                            "{:.02} MB hashed, {:.02} MB/s",
+                            bytes_total as f64 / 1000000.,
+                            bytes_total as f64 / 1000. / elapsed_ms as f64
+                        ),
+                    );
+                }
+                Ok((content_hash, Some((modified, size))))
+            })
+            .collect()
+    });
 
-    let mut file_list_for_trash_files = FileTxt::open_for_read_and_write(path_list_for_trash)?;
+    let mut vec_content_hash = Vec::with_capacity(vec_results.len());
+    for (string_path, result) in vec_paths.iter().zip(vec_results) {
+        let (content_hash, new_in_cache) = result?;
+        if let Some((modified, size)) = new_in_cache {
+            content_hash_cache.insert(string_path, modified, size, content_hash.clone());
+        }
+        vec_content_hash.push(content_hash);
+    }
+    let elapsed_ms = (hash_start.elapsed().as_millis() as u64).max(1);
+    let bytes_total = bytes_hashed.load(Ordering::Relaxed);
     println_to_ui_thread(
-        &ui_tx,
-        format!("{}: {}", file_list_for_trash_files.file_name()?, vec_for_trash.len()),
+        ui_tx,
+        format!(
+            "{:.02} MB hashed in {:.02} s, {:.02} MB/s",
+            bytes_total as f64 / 1000000.,
+            elapsed_ms as f64 / 1000.,
+            bytes_total as f64 / 1000. / elapsed_ms as f64
+        ),
     );
-    let string_for_trash_files = vec_for_trash.join("\n");
-    file_list_for_trash_files.write_append_str(&string_for_trash_files)?;
+    Ok(vec_content_hash)
+}
 
-    let mut file_list_for_downloads = FileTxt::open_for_read_and_write(path_list_for_download)?;
-    println_to_ui_thread(
-        &ui_tx,
-        format!("{}: {}", file_list_for_downloads.file_name()?, vec_for_download.len()),
-    );
-    let string_for_download = vec_for_download.join("\n");
-    file_list_for_downloads.write_append_str(&string_for_download)?;
+/// Detect folders that are moved or renamed as a whole subtree.  \
+///
+/// A folder in the destination is moved, if all its files are for trash and a folder in the source has
+/// all its files for download with the same relative names, sizes and content_hash.  \
+/// The subtrees are found by a signature: the sha256 of the sorted relative path keys and sizes.  \
+/// The local lines have no content_hash, so for an equal signature the content_hash of every pair of files is compared.
+/// It is taken from the line for download and from the cache of local content_hash.  \
+/// Only the topmost folders are used and only if the old folder exists and the new folder does not exist on disk.  \
+/// The files inside the moved folders are removed from the lists for trash and download.
+/// The other files remain for the per-file move, rename, trash and download.  \
+/// The returned lines are `old_folder\tnew_folder\tfiles\tbytes`.  
+fn detect_folder_moves(
+    base_path: &CrossPathBuf,
+    content_hash_cache: &mut ContentHashCache,
+    vec_list_source_files: &[&str],
+    vec_list_destination_files: &[&str],
+    vec_for_download: &mut Vec<String>,
+    vec_for_trash: &mut Vec<String>,
+) -> Result<Vec<String>> {
+    let subtrees_for_trash = subtree_signatures(vec_for_trash, vec_list_destination_files);
+    let subtrees_for_download = subtree_signatures(vec_for_download, vec_list_source_files);
+    let mut index_by_signature: std::collections::HashMap<&str, Vec<&Subtree>> = std::collections::HashMap::new();
+    for subtree in subtrees_for_trash.iter() {
+        index_by_signature.entry(&subtree.signature).or_default().push(subtree);
+    }
 
-    Ok(())
+    let mut vec_for_move_folders: Vec<String> = vec![];
+    let mut set_moved_old_keys: std::collections::HashSet<String> = std::collections::HashSet::new();
+    let mut set_moved_new_keys: std::collections::HashSet<String> = std::collections::HashSet::new();
+    // the topmost folders first, so the subfolders of a moved folder are skipped
+    for subtree_new in subtrees_for_download.iter() {
+        if is_inside_any_folder(&subtree_new.folder_key, &set_moved_new_keys) {
+            continue;
+        }
+        let Some(vec_candidates) = index_by_signature.get(subtree_new.signature.as_str()) else {
+            continue;
+        };
+        for subtree_old in vec_candidates.iter() {
+            if is_inside_any_folder(&subtree_old.folder_key, &set_moved_old_keys) {
+                continue;
+            }
+            if !base_path.join_relative(&subtree_old.folder)?.exists() || base_path.join_relative(&subtree_new.folder)?.exists() {
+                continue;
+            }
+            if !is_equal_subtree_content(base_path, content_hash_cache, subtree_old, subtree_new)? {
+                continue;
+            }
+            vec_for_move_folders.push(format!(
+                "{}\t{}\t{}\t{}",
+                subtree_old.folder, subtree_new.folder, subtree_old.files, subtree_old.bytes
+            ));
+            set_moved_old_keys.insert(subtree_old.folder_key.clone());
+            set_moved_new_keys.insert(subtree_new.folder_key.clone());
+            break;
+        }
+    }
+
+    vec_for_trash.retain(|line| !is_inside_any_folder(&path_key(path_from_line(line)), &set_moved_old_keys));
+    vec_for_download.retain(|line| !is_inside_any_folder(&path_key(path_from_line(line)), &set_moved_new_keys));
+    Ok(vec_for_move_folders)
+}
+
+/// A folder with all its files in the list for trash or download.  
+struct Subtree<'a> {
+    folder: String,
+    folder_key: String,
+    signature: String,
+    /// The relative path keys and the lines of all files in the subtree, sorted like in the signature.
+    entries: Vec<(String, &'a str)>,
+    files: usize,
+    bytes: u64,
+}
+
+/// For every folder key: the original folder path and the relative path keys, sizes and lines of all files in the subtree.  
+type EntriesByFolder<'a> = std::collections::HashMap<String, (String, Vec<(String, &'a str, &'a str)>)>;
+
+/// Returns the subtrees where all the files of the folder are in vec_lines, sorted with topmost folders first.  \
+///
+/// vec_list_all_files is the complete list, to check that no file of the folder stays in place.  
+fn subtree_signatures<'a>(vec_lines: &'a [String], vec_list_all_files: &[&str]) -> Vec<Subtree<'a>> {
+    // for every folder the relative path keys and sizes of all files in the subtree
+    let mut entries_by_folder: EntriesByFolder = std::collections::HashMap::new();
+    for line in vec_lines.iter() {
+        let mut split_line = line.split("\t");
+        let path = split_line.next().unwrap_or_default();
+        let size = split_line.nth(1).unwrap_or_default();
+        for (position, _char) in path.match_indices('/').filter(|(position, _char)| *position > 0) {
+            let folder = &path[..position];
+            entries_by_folder
+                .entry(path_key(folder))
+                .or_insert_with(|| (folder.to_string(), vec![]))
+                .1
+                .push((path_key(&path[position..]), size, line.as_str()));
+        }
+    }
+    if entries_by_folder.is_empty() {
+        return vec![];
+    }
+    // count all files in these folders
+    let mut count_by_folder: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+    for line in vec_list_all_files.iter() {
+        let path = path_from_line(line);
+        for (position, _char) in path.match_indices('/').filter(|(position, _char)| *position > 0) {
+            let folder_key = path_key(&path[..position]);
+            if entries_by_folder.contains_key(&folder_key) {
+                *count_by_folder.entry(folder_key).or_default() += 1;
+            }
+        }
+    }
+
+    let mut vec_subtrees: Vec<Subtree> = entries_by_folder
+        .into_iter()
+        .filter(|(folder_key, (_folder, vec_entries))| count_by_folder.get(folder_key).copied().unwrap_or_default() == vec_entries.len())
+        .map(|(folder_key, (folder, mut vec_entries))| {
+            use rsa::sha2::Digest;
+            vec_entries.sort();
+            let mut hasher = rsa::sha2::Sha256::new();
+            let mut bytes = 0u64;
+            for (relative_key, size, _line) in vec_entries.iter() {
+                hasher.update(format!("{relative_key}\t{size}\n").as_bytes());
+                bytes += size.parse::<u64>().unwrap_or_default();
+            }
+            Subtree {
+                folder,
+                folder_key,
+                signature: format!("{:x}", hasher.finalize()),
+                files: vec_entries.len(),
+                bytes,
+                entries: vec_entries
+                    .into_iter()
+                    .map(|(relative_key, _size, line)| (relative_key, line))
+                    .collect(),
+            }
+        })
+        .collect();
+    vec_subtrees.sort_by(|a, b| {
+        a.folder_key
+            .matches('/')
+            .count()
+            .cmp(&b.folder_key.matches('/').count())
+            .then_with(|| a.folder_key.cmp(&b.folder_key))
+    });
+    vec_subtrees
+}
+
+/// The files of the old local subtree have the same content_hash as the files of the new subtree for download.  \
+///
+/// The entries of both subtrees are sorted by the relative path key, so the files are compared pair by pair.  
+fn is_equal_subtree_content(
+    base_path: &CrossPathBuf,
+    content_hash_cache: &mut ContentHashCache,
+    subtree_old: &Subtree,
+    subtree_new: &Subtree,
+) -> Result<bool> {
+    for ((relative_key_old, line_old), (relative_key_new, line_new)) in subtree_old.entries.iter().zip(subtree_new.entries.iter()) {
+        let content_hash_new = line_new.split("\t").nth(3).unwrap_or_default();
+        if relative_key_old != relative_key_new
+            || content_hash_new.is_empty()
+            || content_hash_cache.content_hash(base_path, path_from_line(line_old))? != content_hash_new
+        {
+            return Ok(false);
+        }
+    }
+    Ok(subtree_old.entries.len() == subtree_new.entries.len())
+}
+
+/// The path key is equal to one of the folder keys or it is inside it.  \
+///
+/// It checks the path and all its parent folders in the set.  
+fn is_inside_any_folder(path_key: &str, set_folder_keys: &std::collections::HashSet<String>) -> bool {
+    set_folder_keys.contains(path_key)
+        || path_key
+            .match_indices('/')
+            .any(|(position, _char)| position > 0 && set_folder_keys.contains(&path_key[..position]))
 }
 
 /// Compare folders and write folders to trash into path_list_for_trash_folders.  \
 ///
-/// The list is already sorted.  
+/// The folders with case-only difference are written into list_for_case_rename_folders.  \
+/// The list is already sorted.  \
+/// Run it after compare_files, because the folders moved as a whole by move_local_folders are not trashed or created.  \
+/// The folder plan header is written last, so trash_folders, create_folders and case_rename_local refuse a stale folder plan.  
+#[allow(clippy::too_many_arguments)]
 pub fn compare_folders(
     ui_tx: std::sync::mpsc::Sender<String>,
-    string_list_source_folders: &str,
-    string_list_destination_folders: &str,
+    ext_disk_base_path: &CrossPathBuf,
+    path_list_source_folders: &CrossPathBuf,
+    path_list_destination_folders: &CrossPathBuf,
+    path_list_destination_special_files: &CrossPathBuf,
+    file_list_for_move_folders: &FileTxt,
     file_list_for_trash_folders: &mut FileTxt,
     file_list_for_create_folders: &mut FileTxt,
+    file_list_for_case_rename_folders: &mut FileTxt,
 ) -> Result<()> {
+    // the moved folders are from the actual file plan
+    verify_plan_header(ext_disk_base_path, file_list_for_move_folders)?;
+    let disk_identity = read_disk_identity(ext_disk_base_path)?;
+    let string_list_source_folders = FileTxt::open_for_read(path_list_source_folders)?.read_to_string()?;
+    let string_list_destination_folders = FileTxt::open_for_read(path_list_destination_folders)?.read_to_string()?;
+    let string_list_for_move_folders = file_list_for_move_folders.read_to_string()?;
     let vec_list_source_folders: Vec<&str> = string_list_source_folders.lines().collect();
     let vec_list_destination_folders: Vec<&str> = string_list_destination_folders.lines().collect();
+    let vec_list_for_move_folders: Vec<&str> = string_list_for_move_folders.lines().collect();
+    let (vec_for_trash, vec_for_create, vec_for_case_rename) =
+        compare_folders_in_memory(&vec_list_source_folders, &vec_list_destination_folders, &vec_list_for_move_folders);
+    // the lines for case rename have the old and the new path
+    let vec_changed_paths: Vec<&str> = vec_for_trash
+        .iter()
+        .chain(vec_for_create.iter())
+        .map(|line| line.as_str())
+        .chain(vec_for_case_rename.iter().flat_map(|line| line.split("\t")))
+        .collect();
+    refuse_changes_under_followed_symlinks(path_list_destination_special_files, &vec_changed_paths)?;
+
+    println_to_ui_thread(
+        &ui_tx,
+        format!("{}: {}", file_list_for_trash_folders.file_name()?, vec_for_trash.len()),
+    );
+    let string_for_trash_files = vec_for_trash.join("\n");
+    file_list_for_trash_folders.replace_atomic(&string_for_trash_files)?;
+    println_to_ui_thread(
+        &ui_tx,
+        format!("{}: {}", file_list_for_create_folders.file_name()?, vec_for_create.len()),
+    );
+    let string_for_create = vec_for_create.join("\n");
+    file_list_for_create_folders.replace_atomic(&string_for_create)?;
+    println_to_ui_thread(
+        &ui_tx,
+        format!("{}: {}", file_list_for_case_rename_folders.file_name()?, vec_for_case_rename.len()),
+    );
+    let string_for_case_rename = vec_for_case_rename.join("\n");
+    file_list_for_case_rename_folders.replace_atomic(&string_for_case_rename)?;
 
+    // the folder plan header is written last, like the plan header in compare_files
+    write_folder_plan_header(
+        file_list_for_trash_folders.file_path(),
+        file_list_for_move_folders.file_path(),
+        &disk_identity.uuid,
+        &[
+            (path_list_source_folders, &string_list_source_folders),
+            (path_list_destination_folders, &string_list_destination_folders),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Refuse a plan that changes a path at or under a symlink followed by list_local.  \
+///
+/// SymlinkPolicy::Follow is only for listing. The target of the symlink can be outside the backup folder,
+/// so trash, move, rename, download or create there would change files outside the backup.  
+fn refuse_changes_under_followed_symlinks(path_list_destination_special_files: &CrossPathBuf, vec_changed_paths: &[&str]) -> Result<()> {
+    if !path_list_destination_special_files.exists() {
+        return Ok(());
+    }
+    let string_list_special_files = FileTxt::open_for_read(path_list_destination_special_files)?.read_to_string()?;
+    let set_followed_keys: std::collections::HashSet<String> = string_list_special_files
+        .lines()
+        .filter_map(|line| line.split_once("\t"))
+        .filter(|(_path, reason)| *reason == REASON_FOLLOWED_SYMLINK)
+        .map(|(path, _reason)| path_key(path.trim_start_matches('/')))
+        .collect();
+    if set_followed_keys.is_empty() {
+        return Ok(());
+    }
+    let vec_refused: Vec<&str> = vec_changed_paths
+        .iter()
+        .filter(|path| is_inside_any_folder(&path_key(path.trim_start_matches('/')), &set_followed_keys))
+        .copied()
+        .collect();
+    if let Some(first_refused) = vec_refused.first() {
+        return Err(Error::ErrorFromString(format!(
+            "The plan changes {} paths under followed symlinks, the first is {first_refused}. SymlinkPolicy::Follow is only for listing.",
+            vec_refused.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Compare the sorted lists of folders in memory. Returns folders for trash, folders for create and folders for case rename.  \
+///
+/// The lines for case rename are `old_path\tnew_path`.  \
+/// The old folders from list_for_move_folders and their subfolders are not trashed,
+/// the new folders and their subfolders are not created. move_local_folders renames them as a whole.  
+pub(crate) fn compare_folders_in_memory(
+    vec_list_source_folders: &[&str],
+    vec_list_destination_folders: &[&str],
+    vec_list_for_move_folders: &[&str],
+) -> (Vec<String>, Vec<String>, Vec<String>) {
     let mut vec_for_trash: Vec<String> = vec![];
-    file_list_for_trash_folders.empty()?;
     let mut vec_for_create: Vec<String> = vec![];
-    file_list_for_create_folders.empty()?;
+    let mut vec_for_case_rename: Vec<String> = vec![];
     let mut cursor_source = 0;
     let mut cursor_destination = 0;
 
@@ -206,35 +977,51 @@ pub fn compare_folders(
             cursor_destination += 1;
         } else {
             // compare the 2 lines
-            // UncasedStr preserves the case in the string, but comparison is done case insensitive
-            let path_source: &UncasedStr = vec_list_source_folders[cursor_source].into();
-            let path_destination: &UncasedStr = vec_list_destination_folders[cursor_destination].into();
-            if path_source.lt(path_destination) {
+            // the path key is lowercase and unicode normalized, but the original path is used on disk
+            let path_source = path_key(vec_list_source_folders[cursor_source]);
+            let path_destination = path_key(vec_list_destination_folders[cursor_destination]);
+            if path_source < path_destination {
                 vec_for_create.push(vec_list_source_folders[cursor_source].to_string());
                 cursor_source += 1;
-            } else if path_source.gt(path_destination) {
+            } else if path_source > path_destination {
                 vec_for_trash.push(vec_list_destination_folders[cursor_destination].to_string());
                 cursor_destination += 1;
             } else {
-                // else no action, just increment cursors
+                // equal names, but the case of the folder name can be different
+                if is_case_only_rename(
+                    vec_list_destination_folders[cursor_destination],
+                    vec_list_source_folders[cursor_source],
+                ) {
+                    vec_for_case_rename.push(format!(
+                        "{}\t{}",
+                        vec_list_destination_folders[cursor_destination], vec_list_source_folders[cursor_source]
+                    ));
+                }
                 cursor_destination += 1;
                 cursor_source += 1;
             }
         }
     }
-    println_to_ui_thread(
-        &ui_tx,
-        format!("{}: {}", file_list_for_trash_folders.file_name()?, vec_for_trash.len()),
-    );
-    let string_for_trash_files = vec_for_trash.join("\n");
-    file_list_for_trash_folders.write_append_str(&string_for_trash_files)?;
-    println_to_ui_thread(
-        &ui_tx,
-        format!("{}: {}", file_list_for_create_folders.file_name()?, vec_for_create.len()),
-    );
-    let string_for_create = vec_for_create.join("\n");
-    file_list_for_create_folders.write_append_str(&string_for_create)?;
-    Ok(())
+    let mut set_moved_old_keys: std::collections::HashSet<String> = std::collections::HashSet::new();
+    let mut set_moved_new_keys: std::collections::HashSet<String> = std::collections::HashSet::new();
+    for line in vec_list_for_move_folders.iter() {
+        let mut split_line = line.split("\t");
+        set_moved_old_keys.insert(path_key(split_line.next().unwrap_or_default()));
+        set_moved_new_keys.insert(path_key(split_line.next().unwrap_or_default()));
+    }
+    vec_for_trash.retain(|folder| !is_inside_any_folder(&path_key(folder), &set_moved_old_keys));
+    vec_for_create.retain(|folder| !is_inside_any_folder(&path_key(folder), &set_moved_new_keys));
+    (vec_for_trash, vec_for_create, vec_for_case_rename)
+}
+
+/// The paths are equal case insensitive, but the last part of the path differs in case.  \
+///
+/// If only the parent folders differ in case, they are renamed with the folders and not here.  \
+/// A difference only in unicode normalization is not a rename, because it is the same name.  
+fn is_case_only_rename(old_path: &str, new_path: &str) -> bool {
+    let old_name = old_path.rsplit_once('/').map_or(old_path, |(_parent, name)| name);
+    let new_name = new_path.rsplit_once('/').map_or(new_path, |(_parent, name)| name);
+    !is_equal_normalized(old_name, new_name)
 }
 
 /*
@@ -309,3 +1096,188 @@ fn add_just_downloaded_to_list_local_internal(path_list_just_downloaded: &str, p
     }
 }
  */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils_mod::new_test_folder;
+
+    /// Write a local test file and return its line for the local list and the content_hash.
+    fn write_local_file(base_path: &CrossPathBuf, path: &str, content: &str) -> (String, String) {
+        let path_global = base_path.join_relative(path).unwrap();
+        path_global.create_dir_all_for_file().unwrap();
+        std::fs::write(path_global.to_path_buf_current_os(), content).unwrap();
+        let content_hash = format!(
+            "{:x}",
+            DropboxContentHasher::hash_file(path_global.to_path_buf_current_os()).unwrap()
+        );
+        (format!("{path}\t2024-01-01T00:00:00Z\t{}", content.len()), content_hash)
+    }
+
+    #[test]
+    fn detect_folder_moves_moves_the_topmost_folder() {
+        let base_path = new_test_folder("detect_folder_moves");
+        let mut content_hash_cache = ContentHashCache::load(&base_path).unwrap();
+        let (line_a, hash_a) = write_local_file(&base_path, "/old/a.txt", "aaaaaaaaaa");
+        let (line_b, hash_b) = write_local_file(&base_path, "/old/sub/b.txt", "bbbbbbbbbbbbbbbbbbbb");
+        let vec_list_destination_files = vec![line_a.as_str(), line_b.as_str(), "/stay.txt\t2024-01-01T00:00:00Z\t5"];
+        let line_new_a = format!("/new/a.txt\t2024-01-01T00:00:00Z\t10\t{hash_a}");
+        let line_new_b = format!("/new/sub/b.txt\t2024-01-01T00:00:00Z\t20\t{hash_b}");
+        let vec_list_source_files = vec![
+            line_new_a.as_str(),
+            line_new_b.as_str(),
+            "/other.txt\t2024-01-01T00:00:00Z\t7\thash_other",
+            "/stay.txt\t2024-01-01T00:00:00Z\t5\thash_stay",
+        ];
+        let mut vec_for_trash: Vec<String> = vec_list_destination_files[..2].iter().map(|line| line.to_string()).collect();
+        let mut vec_for_download: Vec<String> = vec_list_source_files[..3].iter().map(|line| line.to_string()).collect();
+        let vec_for_move_folders = detect_folder_moves(
+            &base_path,
+            &mut content_hash_cache,
+            &vec_list_source_files,
+            &vec_list_destination_files,
+            &mut vec_for_download,
+            &mut vec_for_trash,
+        )
+        .unwrap();
+        assert_eq!(vec_for_move_folders, vec!["/old\t/new\t2\t30".to_string()]);
+        assert!(vec_for_trash.is_empty());
+        assert_eq!(
+            vec_for_download,
+            vec!["/other.txt\t2024-01-01T00:00:00Z\t7\thash_other".to_string()]
+        );
+    }
+
+    #[test]
+    fn detect_folder_moves_needs_the_whole_subtree() {
+        let base_path = new_test_folder("detect_folder_moves_partial");
+        let mut content_hash_cache = ContentHashCache::load(&base_path).unwrap();
+        // one file stays in the old folder, so the folder is not moved as a whole
+        let (line_a, hash_a) = write_local_file(&base_path, "/old/a.txt", "aaaaaaaaaa");
+        let (line_keep, hash_keep) = write_local_file(&base_path, "/old/keep.txt", "k");
+        let vec_list_destination_files = vec![line_a.as_str(), line_keep.as_str()];
+        let line_new_a = format!("/new/a.txt\t2024-01-01T00:00:00Z\t10\t{hash_a}");
+        let line_source_keep = format!("{line_keep}\t{hash_keep}");
+        let vec_list_source_files = vec![line_new_a.as_str(), line_source_keep.as_str()];
+        let mut vec_for_trash = vec![vec_list_destination_files[0].to_string()];
+        let mut vec_for_download = vec![vec_list_source_files[0].to_string()];
+        let vec_for_move_folders = detect_folder_moves(
+            &base_path,
+            &mut content_hash_cache,
+            &vec_list_source_files,
+            &vec_list_destination_files,
+            &mut vec_for_download,
+            &mut vec_for_trash,
+        )
+        .unwrap();
+        assert!(vec_for_move_folders.is_empty());
+        assert_eq!(vec_for_trash.len(), 1);
+        assert_eq!(vec_for_download.len(), 1);
+    }
+
+    #[test]
+    fn detect_folder_moves_needs_equal_content_hash() {
+        let base_path = new_test_folder("detect_folder_moves_content");
+        let mut content_hash_cache = ContentHashCache::load(&base_path).unwrap();
+        // same relative names and sizes, but other content
+        let (line_a, _hash_a) = write_local_file(&base_path, "/old/a.txt", "aaaaaaaaaa");
+        let vec_list_destination_files = vec![line_a.as_str()];
+        let (_line_other, hash_other) = write_local_file(&base_path, "/other/a.txt", "zzzzzzzzzz");
+        let line_new_a = format!("/new/a.txt\t2024-01-01T00:00:00Z\t10\t{hash_other}");
+        let vec_list_source_files = vec![line_new_a.as_str()];
+        let mut vec_for_trash = vec![vec_list_destination_files[0].to_string()];
+        let mut vec_for_download = vec![vec_list_source_files[0].to_string()];
+        let vec_for_move_folders = detect_folder_moves(
+            &base_path,
+            &mut content_hash_cache,
+            &vec_list_source_files,
+            &vec_list_destination_files,
+            &mut vec_for_download,
+            &mut vec_for_trash,
+        )
+        .unwrap();
+        assert!(vec_for_move_folders.is_empty());
+        assert_eq!(vec_for_trash.len(), 1);
+        assert_eq!(vec_for_download.len(), 1);
+    }
+
+    #[test]
+    fn compare_folders_skips_moved_folders() {
+        let vec_list_source_folders = vec!["/created", "/new", "/new/sub"];
+        let vec_list_destination_folders = vec!["/old", "/old/sub", "/trashed"];
+        let vec_list_for_move_folders = vec!["/old\t/new\t2\t30"];
+        let (vec_for_trash, vec_for_create, vec_for_case_rename) =
+            compare_folders_in_memory(&vec_list_source_folders, &vec_list_destination_folders, &vec_list_for_move_folders);
+        assert_eq!(vec_for_trash, vec!["/trashed".to_string()]);
+        assert_eq!(vec_for_create, vec!["/created".to_string()]);
+        assert!(vec_for_case_rename.is_empty());
+    }
+
+    #[test]
+    fn timestamp_tolerance_is_detected_once() {
+        let base_path = new_test_folder("timestamp_tolerance");
+        let compare_options = CompareOptions::default();
+        // the dry-run does not write the probe
+        assert_eq!(
+            compare_options.timestamp_tolerance_read_only(&base_path).unwrap(),
+            chrono::Duration::seconds(2)
+        );
+        assert!(!base_path.join_relative("0_backup_temp").unwrap().exists());
+        let timestamp_tolerance = compare_options.timestamp_tolerance(&base_path).unwrap();
+        assert_eq!(read_timestamp_tolerance(&base_path).unwrap(), Some(timestamp_tolerance));
+        assert_eq!(
+            compare_options.timestamp_tolerance_read_only(&base_path).unwrap(),
+            timestamp_tolerance
+        );
+    }
+
+    #[test]
+    fn changes_under_followed_symlinks_are_refused() {
+        let base_path = new_test_folder("followed_symlinks");
+        let path_list_special_files = base_path.join_relative("list_destination_special_files.csv").unwrap();
+        std::fs::write(
+            path_list_special_files.to_path_buf_current_os(),
+            "link\tfollowed_symlink\nsocket\tsocket",
+        )
+        .unwrap();
+        assert!(refuse_changes_under_followed_symlinks(&path_list_special_files, &["/other/a.txt", "/socket", "/linked.txt"]).is_ok());
+        assert!(refuse_changes_under_followed_symlinks(&path_list_special_files, &["/other/a.txt", "/Link/a.txt"]).is_err());
+        assert!(refuse_changes_under_followed_symlinks(&path_list_special_files, &["link"]).is_err());
+    }
+
+    #[test]
+    fn missing_local_copy_has_its_own_reason() {
+        let (ui_tx, _ui_rx) = std::sync::mpsc::channel();
+        let base_path = new_test_folder("missing_local_copy");
+        let compare_options = CompareOptions {
+            timestamp_tolerance: Some(chrono::Duration::seconds(2)),
+            ..Default::default()
+        };
+        let vec_list_source_files = vec!["/a.txt\t2024-01-01T00:00:00Z\t1\thash_a", "/b.txt\t2024-01-01T00:00:00Z\t2\thash_b"];
+        // the last compare knew a.txt in the local backup, now it is missing
+        write_compared_local_paths(&base_path, &["/A.txt\t2024-01-01T00:00:00Z\t1"], &[]).unwrap();
+        for _ in 0..2 {
+            let set_compared_local_paths = read_compared_local_paths(&base_path).unwrap();
+            let mut content_hash_cache = ContentHashCache::load(&base_path).unwrap();
+            let compared_files = compare_files_in_memory(
+                &ui_tx,
+                &base_path,
+                &compare_options,
+                chrono::Duration::seconds(2),
+                &mut content_hash_cache,
+                &set_compared_local_paths,
+                &vec_list_source_files,
+                &[],
+            )
+            .unwrap();
+            let vec_reasons: Vec<&str> = compared_files
+                .vec_for_download
+                .iter()
+                .map(|line| line.split("\t").nth(REASON_COLUMN_FOR_DOWNLOAD).unwrap())
+                .collect();
+            assert_eq!(vec_reasons, vec![REASON_MISSING, REASON_NEW]);
+            // the next compare still knows the missing file
+            write_compared_local_paths(&base_path, &[], &compared_files.vec_for_download).unwrap();
+        }
+    }
+}
@@ -0,0 +1,129 @@
+// content_hash_cache_mod.rs
+
+//! Persistent cache of the local content_hash.
+//!
+//! Calculating the content_hash of big files on a slow USB disk takes a lot of time.  
+//! The cache is stored on the external disk in `0_backup_temp/content_hash_cache.csv`.  
+//! Tab delimited: path, modified, size, content_hash.  
+//! A cached content_hash is valid only if the size and modified of the local file are still equal.  
+//! Else it is calculated again and the cache is updated.
+
+use crossplatform_path::CrossPathBuf;
+use dropbox_content_hasher::DropboxContentHasher;
+
+use crate::error_mod::Result;
+
+// type alias for better expressing coder intention,
+// but programmatically identical to the underlying type
+type Modified = String;
+type Size = u64;
+type ContentHash = String;
+
+/// Cache of content_hash of the local files.  
+pub struct ContentHashCache {
+    path_cache: CrossPathBuf,
+    hash_map: std::collections::HashMap<String, (Modified, Size, ContentHash)>,
+    is_changed: bool,
+}
+
+impl ContentHashCache {
+    /// Load the cache from the external disk. If the file does not exist, the cache is empty.  
+    pub fn load(ext_disk_base_path: &CrossPathBuf) -> Result<Self> {
+        let path_cache = ext_disk_base_path
+            .join_relative("0_backup_temp")?
+            .join_relative("content_hash_cache.csv")?;
+        let mut hash_map = std::collections::HashMap::new();
+        if path_cache.exists() {
+            let string_cache = path_cache.read_to_string()?;
+            for line in string_cache.lines() {
+                let vec_line: Vec<&str> = line.split("\t").collect();
+                // ignore broken lines, the cache will be repaired on the next use
+                if vec_line.len() == 4 {
+                    if let Ok(size) = vec_line[2].parse::<u64>() {
+                        hash_map.insert(vec_line[0].to_string(), (vec_line[1].to_string(), size, vec_line[3].to_string()));
+                    }
+                }
+            }
+        }
+        Ok(ContentHashCache {
+            path_cache,
+            hash_map,
+            is_changed: false,
+        })
+    }
+
+    /// Returns the content_hash of the local file.  \
+    ///
+    /// It is taken from cache, if the size and modified are equal. Else it is calculated and the cache is updated.  
+    pub fn content_hash(&mut self, ext_disk_base_path: &CrossPathBuf, string_path: &str) -> Result<String> {
+        let path_global = ext_disk_base_path.join_relative(string_path)?;
+        let (modified, size) = local_modified_and_size(&path_global)?;
+        if let Some(content_hash) = self.get_valid(string_path, &modified, size) {
+            return Ok(content_hash.to_string());
+        }
+        let content_hash = format!("{:x}", DropboxContentHasher::hash_file(path_global.to_path_buf_current_os())?);
+        self.insert(string_path, modified, size, content_hash.clone());
+        Ok(content_hash)
+    }
+
+    /// Returns the cached content_hash only if the size and modified are equal.  
+    pub fn get_valid(&self, string_path: &str, modified: &str, size: u64) -> Option<&str> {
+        match self.hash_map.get(string_path) {
+            Some((cached_modified, cached_size, content_hash)) if cached_modified == modified && *cached_size == size => {
+                Some(content_hash.as_str())
+            }
+            _ => None,
+        }
+    }
+
+    /// Insert or replace the content_hash for the local file.  
+    pub fn insert(&mut self, string_path: &str, modified: String, size: u64, content_hash: String) {
+        self.hash_map.insert(string_path.to_string(), (modified, size, content_hash));
+        self.is_changed = true;
+    }
+
+    /// Insert the known content_hash of a file that was just written, with the size and modified read from the disk.  
+    pub fn insert_from_disk(&mut self, ext_disk_base_path: &CrossPathBuf, string_path: &str, content_hash: &str) -> Result<()> {
+        let path_global = ext_disk_base_path.join_relative(string_path)?;
+        let (modified, size) = local_modified_and_size(&path_global)?;
+        self.insert(string_path, modified, size, content_hash.to_string());
+        Ok(())
+    }
+
+    /// Remove the paths that are not in the local backup anymore.  
+    pub fn retain_paths(&mut self, set_paths: &std::collections::HashSet<&str>) {
+        let len_before = self.hash_map.len();
+        self.hash_map.retain(|path, _value| set_paths.contains(path.as_str()));
+        if self.hash_map.len() != len_before {
+            self.is_changed = true;
+        }
+    }
+
+    /// Save the cache to the external disk, only if something changed.  \
+    ///
+    /// It writes a temp file and then renames it, so the cache is never half written.  
+    pub fn save(&mut self) -> Result<()> {
+        if !self.is_changed {
+            return Ok(());
+        }
+        let vec_lines: Vec<String> = self
+            .hash_map
+            .iter()
+            .map(|(path, (modified, size, content_hash))| format!("{path}\t{modified}\t{size}\t{content_hash}"))
+            .collect();
+        let string_cache = crate::utils_mod::sort_list(vec_lines);
+        self.path_cache.create_dir_all_for_file()?;
+        let path_temp = CrossPathBuf::new(&format!("{}.tmp", self.path_cache))?;
+        std::fs::write(path_temp.to_path_buf_current_os(), string_cache)?;
+        std::fs::rename(path_temp.to_path_buf_current_os(), self.path_cache.to_path_buf_current_os())?;
+        self.is_changed = false;
+        Ok(())
+    }
+}
+
+/// Read modified and size of the local file. The modified has nanoseconds, to recognize also quick changes.  
+pub fn local_modified_and_size(path_global: &CrossPathBuf) -> Result<(String, u64)> {
+    let metadata = std::fs::metadata(path_global.to_path_buf_current_os())?;
+    let modified: chrono::DateTime<chrono::Utc> = metadata.modified()?.into();
+    Ok((modified.to_rfc3339_opts(chrono::SecondsFormat::Nanos, true), metadata.len()))
+}
@@ -0,0 +1,139 @@
+// disk_identity_mod.rs
+
+//! Identity of the backup disk in the file `0_backup_identity.json` in the backup root.
+//!
+//! If ext_disk_base_path points to the wrong disk or to an empty mount point, the compare would trash
+//! the whole other backup or download everything again. The identity file protects from this mistake.
+//! It contains a random uuid, the Dropbox account id and the include roots of the backup.
+//! It is written only once with initialize_disk_identity, that is an explicit decision of the user.
+//! Compare refuses to run on a disk without identity and writes the uuid into the plan header.
+//! The steps that change the disk verify the plan header, so they refuse to run on another disk.
+
+use crossplatform_path::CrossPathBuf;
+
+use crate::error_mod::{Error, Result};
+use crate::utils_mod::println_to_ui_thread;
+
+/// File name of the disk identity in the backup root. It is not in the local list.
+pub(crate) const DISK_IDENTITY_FILE_NAME: &str = "0_backup_identity.json";
+
+/// The content of the identity file.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DiskIdentity {
+    /// Random uuid version 4, created once for this backup disk.
+    pub uuid: String,
+    /// The Dropbox account id of the backup.
+    pub account_id: String,
+    /// The remote folders in this backup. The root `/` means the whole Dropbox.
+    pub include_roots: Vec<String>,
+    /// Datetime of initialization.
+    pub created: String,
+}
+
+/// Write the identity file to a new backup disk.  \
+///
+/// This is the only way to make a disk usable for sync. It refuses to overwrite an existing identity.
+/// To initialize the disk again, the user must delete the identity file manually.
+pub fn initialize_disk_identity(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    account_id: &str,
+    include_roots: &[String],
+) -> Result<DiskIdentity> {
+    if !ext_disk_base_path.exists() {
+        return Err(Error::ErrorFromString(format!(
+            "backup folder does not exist: {ext_disk_base_path}"
+        )));
+    }
+    let path_identity = ext_disk_base_path.join_relative(DISK_IDENTITY_FILE_NAME)?;
+    if path_identity.exists() {
+        return Err(Error::ErrorFromString(format!(
+            "The disk is already initialized. Delete {path_identity} to initialize it again."
+        )));
+    }
+    let disk_identity = DiskIdentity {
+        uuid: new_uuid_v4(),
+        account_id: account_id.to_string(),
+        include_roots: include_roots.to_vec(),
+        created: chrono::Utc::now().format("%Y-%m-%dT%TZ").to_string(),
+    };
+    // write to temp file and rename, so the identity file is never incomplete
+    let path_identity = path_identity.to_path_buf_current_os();
+    let mut path_temp = path_identity.clone().into_os_string();
+    path_temp.push(".tmp");
+    std::fs::write(&path_temp, serde_json::to_string_pretty(&disk_identity)?)?;
+    std::fs::rename(&path_temp, &path_identity)?;
+    println_to_ui_thread(&ui_tx, format!("disk initialized with uuid {}", disk_identity.uuid));
+    Ok(disk_identity)
+}
+
+/// Read the identity file. It returns an error if the disk is not initialized.
+pub fn read_disk_identity(ext_disk_base_path: &CrossPathBuf) -> Result<DiskIdentity> {
+    let path_identity = ext_disk_base_path.join_relative(DISK_IDENTITY_FILE_NAME)?;
+    if !path_identity.exists() {
+        return Err(Error::ErrorFromString(format!(
+            "{DISK_IDENTITY_FILE_NAME} does not exist in {ext_disk_base_path}. Wrong disk or empty mount point? Run initialize_disk_identity only for a new backup disk."
+        )));
+    }
+    let disk_identity: DiskIdentity = serde_json::from_str(&path_identity.read_to_string()?)?;
+    Ok(disk_identity)
+}
+
+/// Verify that the disk is the backup of this Dropbox account with these include roots.  \
+///
+/// Call it before list and compare, when the account id is known from the remote.
+pub fn verify_disk_identity(ext_disk_base_path: &CrossPathBuf, account_id: &str, include_roots: &[String]) -> Result<DiskIdentity> {
+    let disk_identity = read_disk_identity(ext_disk_base_path)?;
+    if disk_identity.account_id != account_id {
+        return Err(Error::ErrorFromString(format!(
+            "The disk {ext_disk_base_path} is the backup of another Dropbox account: {}",
+            disk_identity.account_id
+        )));
+    }
+    if disk_identity.include_roots != include_roots {
+        return Err(Error::ErrorFromString(format!(
+            "The disk {ext_disk_base_path} is the backup of other include roots: {}",
+            disk_identity.include_roots.join(", ")
+        )));
+    }
+    Ok(disk_identity)
+}
+
+/// Random uuid version 4 from the OS random generator.
+fn new_uuid_v4() -> String {
+    let mut random_bytes = [0_u8; 16];
+    use aes_gcm::aead::rand_core::RngCore;
+    aes_gcm::aead::OsRng.fill_bytes(&mut random_bytes);
+    // version 4 and variant bits
+    random_bytes[6] = (random_bytes[6] & 0x0f) | 0x40;
+    random_bytes[8] = (random_bytes[8] & 0x3f) | 0x80;
+    let hex: String = random_bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils_mod::new_test_folder;
+
+    #[test]
+    fn uninitialized_disk_is_refused() {
+        let base_path = new_test_folder("identity_uninitialized");
+        assert!(read_disk_identity(&base_path).is_err());
+        assert!(verify_disk_identity(&base_path, "dbid:1", &["/".to_string()]).is_err());
+    }
+
+    #[test]
+    fn wrong_account_or_include_roots_is_refused() {
+        let base_path = new_test_folder("identity_wrong");
+        let (ui_tx, _ui_rx) = std::sync::mpsc::channel();
+        let include_roots = vec!["/Photos".to_string()];
+        let disk_identity = initialize_disk_identity(ui_tx.clone(), &base_path, "dbid:1", &include_roots).unwrap();
+        assert_eq!(verify_disk_identity(&base_path, "dbid:1", &include_roots).unwrap(), disk_identity);
+        assert!(verify_disk_identity(&base_path, "dbid:2", &include_roots).is_err());
+        assert!(verify_disk_identity(&base_path, "dbid:1", &["/".to_string()]).is_err());
+        // the identity is written only once
+        assert!(initialize_disk_identity(ui_tx, &base_path, "dbid:2", &include_roots).is_err());
+        assert_eq!(read_disk_identity(&base_path).unwrap().account_id, "dbid:1");
+    }
+}
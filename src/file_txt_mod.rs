@@ -52,8 +52,11 @@ impl FileTxt {
         self.file_path.read_to_string()
     }
 
-    /// Append str to file.
+    /// Append str to file.  \
+    ///
+    /// It always writes at the end of the file, also if the file had content when it was opened or after empty().  
     pub fn write_append_str(&mut self, str: &str) -> std::io::Result<()> {
+        std::io::Seek::seek(&mut self.file_txt, std::io::SeekFrom::End(0))?;
         std::io::Write::write_all(&mut self.file_txt, str.as_bytes())?;
         Ok(())
     }
@@ -63,4 +66,22 @@ impl FileTxt {
         self.file_txt.set_len(0)?;
         Ok(())
     }
+
+    /// Replace the content of the file atomically.  \
+    ///
+    /// The content is written into a temporary file in the same folder and then renamed over the old file.  \
+    /// If anything fails, the old content stays intact. Calling it twice with the same content gives the same file.  
+    pub fn replace_atomic(&mut self, str: &str) -> std::io::Result<()> {
+        let path = self.file_path.to_path_buf_current_os();
+        let mut path_temp = path.clone().into_os_string();
+        path_temp.push(".tmp");
+        let mut file_temp = std::fs::File::create(&path_temp)?;
+        std::io::Write::write_all(&mut file_temp, str.as_bytes())?;
+        file_temp.sync_all()?;
+        drop(file_temp);
+        std::fs::rename(&path_temp, &path)?;
+        // the old file handle points to the replaced file
+        self.file_txt = std::fs::File::options().read(true).write(true).open(&path)?;
+        Ok(())
+    }
 }
@@ -4,24 +4,45 @@
 
 mod app_state_mod;
 mod compare_mod;
+mod content_hash_cache_mod;
+mod disk_identity_mod;
 mod dropbox_api_token_with_oauth2_mod;
 mod encrypt_decrypt_mod;
 mod error_mod;
 mod file_txt_mod;
 mod local_disk_mod;
+mod mirror_set_mod;
+mod plan_mod;
 mod remote_dropbox_mod;
+mod scrub_mod;
+mod snapshot_mod;
+mod trash_mod;
 mod utils_mod;
 
 // export public code to the bin project
 pub use crate::app_state_mod::{global_app_state, global_config, AppConfig, AppStateMethods, APP_STATE};
-pub use crate::compare_mod::{compare_files, compare_folders};
+pub use crate::compare_mod::{
+    compare_files, compare_folders, detect_timestamp_tolerance, CompareOptions, REASON_CONTENT_CHANGED, REASON_CORRUPTED, REASON_MISSING,
+    REASON_MODIFIED_CHANGED, REASON_NEW, REASON_NOT_IN_SOURCE, REASON_SIZE_CHANGED,
+};
+pub use crate::disk_identity_mod::{initialize_disk_identity, read_disk_identity, verify_disk_identity, DiskIdentity};
 pub use crate::dropbox_api_token_with_oauth2_mod::dropbox_api_config_initialize;
 pub use crate::error_mod::{Error, Result};
 pub use crate::file_txt_mod::FileTxt;
 pub use crate::local_disk_mod::{
-    change_time_files, create_folders, list_local, move_local_files, read_only_remove, rename_local_files, trash_files, trash_folders,
+    case_rename_local, change_time_files, copy_local_files, create_folders, list_local, move_local_files, move_local_folders,
+    purge_versions, read_only_remove, read_only_restore, rename_local_files, trash_files, trash_folders, SymlinkPolicy,
+};
+pub use crate::mirror_set_mod::{compare_mirror_set, copy_from_mirror, MirrorSet};
+pub use crate::plan_mod::{plan_sync, verify_folder_plan_header, verify_plan_header, PlanStep, SyncPlan};
+pub use crate::remote_dropbox_mod::{
+    download_from_list, download_one_file, download_revisions, encode_token, list_remote, remote_account_id, test_connection,
+};
+pub use crate::scrub_mod::scrub;
+pub use crate::snapshot_mod::{create_snapshot, list_snapshots, prune_snapshots, BackupSnapshot};
+pub use crate::trash_mod::{
+    list_trash, purge_trash, restore_from_trash, search_trash, RestoreConflict, TrashFile, TrashPurgePolicy, TrashSnapshot,
 };
-pub use crate::remote_dropbox_mod::{download_from_list, download_one_file, encode_token, list_remote, test_connection};
 pub use crate::utils_mod::{shorten_string, sort_string_lines};
 
 /*
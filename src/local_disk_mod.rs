@@ -18,21 +18,56 @@ use uncased::UncasedStr;
 use unwrap::unwrap; */
 
 use crate::{
+    compare_mod::{count_by_reason, CompareOptions, REASON_COLUMN_FOR_DOWNLOAD},
+    content_hash_cache_mod::ContentHashCache,
+    disk_identity_mod::{read_disk_identity, DISK_IDENTITY_FILE_NAME},
     error_mod::{Error, Result},
-    utils_mod::{println_to_ui_thread, println_to_ui_thread_with_thread_name},
+    plan_mod::{verify_folder_plan_header, verify_plan_header},
+    snapshot_mod::{break_hardlink, SNAPSHOTS_FOLDER_NAME},
+    utils_mod::{path_key, println_to_ui_thread, println_to_ui_thread_with_thread_name},
     FileTxt,
 };
 
+/// Default number of threads for the parallel walk of the local disk, the same as for the remote list.
+const DEFAULT_WALK_THREADS: usize = 8;
+
+/// What list_local does with symlinks in the backup folder.  \
+///
+/// Dropbox does not sync symlinks, so normally there are none on the external disk.  
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Ignore symlinks silently.
+    Skip,
+    /// Ignore symlinks and write them to the list of special files.
+    #[default]
+    Report,
+    /// Follow symlinks to files and folders, only for listing. A symlink that makes a loop is not followed and it is reported.  \
+    /// The target can be outside the backup folder, so every followed symlink is written to the list of special files
+    /// and compare refuses a plan that would change anything under it.
+    Follow,
+}
+
+/// The reason in the list of special files for a symlink that was followed with SymlinkPolicy::Follow.
+pub(crate) const REASON_FOLLOWED_SYMLINK: &str = "followed_symlink";
+
 /// The logic is in the LIB project, but all UI is in the CLI project.  \
 ///
 /// They run on different threads and communicate.  \
-/// It uses the global APP_STATE for all config data.  
+/// It uses the global APP_STATE for all config data.  \
+/// The folders are read in parallel on a threadpool with walk_threads (default 8), every folder is a separate task.  \
+/// The output is sorted, so it is the same as from a single-threaded walk.  \
+/// Special files (sockets, fifos, devices) are never in the list of files, so they are never trashed or hashed.  \
+/// They are written with the reason to the list of special files, together with the reported symlinks.  
+#[allow(clippy::too_many_arguments)]
 pub fn list_local(
     ui_tx: std::sync::mpsc::Sender<(String, ThreadName)>,
     ext_disk_base_path: String,
+    walk_threads: Option<usize>,
+    symlink_policy: SymlinkPolicy,
     mut file_list_destination_files: FileTxt,
     mut file_list_destination_folders: FileTxt,
     mut file_list_destination_readonly_files: FileTxt,
+    mut file_list_destination_special_files: FileTxt,
 ) -> Result<()> {
     let list_local_start = std::time::Instant::now();
 
@@ -40,87 +75,58 @@ pub fn list_local(
     file_list_destination_files.empty()?;
     file_list_destination_folders.empty()?;
     file_list_destination_readonly_files.empty()?;
+    file_list_destination_special_files.empty()?;
 
-    // write data to a big string in memory (for my use-case it is >25 MB)
-    let mut files_string = String::with_capacity(40_000_000);
-    let mut folders_string = String::new();
-    let mut readonly_files_string = String::new();
-    use walkdir::WalkDir;
+    // write data to big vectors in memory (for my use-case it is >25 MB)
+    let mut vec_files: Vec<String> = Vec::with_capacity(400_000);
+    let mut vec_folders: Vec<String> = vec![];
+    let mut vec_readonly_files: Vec<String> = vec![];
+    let mut vec_special_files: Vec<String> = vec![];
 
-    let mut folder_count = 0;
-    let mut file_count = 0;
-    let mut last_send_ms = std::time::Instant::now();
-    let walkdir_iterator = WalkDir::new(&ext_disk_base_path);
-    for entry in walkdir_iterator {
-        //let mut ns_started = ns_start("WalkDir entry start");
-        let entry: walkdir::DirEntry = entry?;
-        let path = entry.path();
-        let str_path = path.to_str().ok_or_else(|| Error::ErrorFromStr("Error string is not path"))?;
-        // I don't need the "base" folder in this list. The ext_disk_base_path always ends with slash.
-        let str_path_wo_base = str_path.trim_start_matches(&ext_disk_base_path);
-        // change windows style with backslash to Linux style with neutral crossplatform slash
-        let str_path_wo_base = str_path_wo_base.replace(r#"\"#, "/");
-        let str_path_wo_base = &str_path_wo_base;
-        // path.is_dir() is slow. entry.file-type().is_dir() is fast
-        if entry.file_type().is_dir() {
-            if !str_path_wo_base.is_empty() {
-                // avoid the temp_trash folder
-                if !str_path_wo_base.starts_with("0_backup_temp") {
-                    folders_string.push_str(&format!("{}\n", str_path_wo_base));
-                    // don't print every folder, because print is slow. Check if 100ms passed
-                    if last_send_ms.elapsed().as_millis() >= 100 {
-                        println_to_ui_thread_with_thread_name(
-                            &ui_tx,
-                            format!("{file_count}: {}", crate::shorten_string(str_path_wo_base, 80)?),
-                            "L",
-                        );
-
-                        last_send_ms = std::time::Instant::now();
-                    }
-                    folder_count += 1;
-                }
-            }
-        } else {
-            // write csv tab delimited
-            // metadata() in wsl/Linux is slow. Nothing to do here.
-            if let Ok(metadata) = entry.metadata() {
-                if !str_path_wo_base.starts_with("0_backup_temp") {
-                    use chrono::offset::Utc;
-                    use chrono::DateTime;
-                    let datetime: DateTime<Utc> = metadata.modified()?.into();
-
-                    if metadata.permissions().readonly() {
-                        readonly_files_string.push_str(&format!("{}\n", str_path_wo_base,));
-                    }
-                    files_string.push_str(&format!(
-                        "{}\t{}\t{}\n",
-                        str_path_wo_base,
-                        datetime.format("%Y-%m-%dT%TZ"),
-                        metadata.len()
-                    ));
-
-                    file_count += 1;
-                }
-            }
-        }
+    let walk_context = LocalWalkContext {
+        ext_disk_base_path: &ext_disk_base_path,
+        symlink_policy,
+        ui_tx: ui_tx.clone(),
+        file_count: std::sync::atomic::AtomicUsize::new(0),
+        last_send_ms: std::sync::Mutex::new(std::time::Instant::now()),
+    };
+    let walk_context = &walk_context;
+    // channel for inter-thread communication to send the lists of every folder
+    let (list_tx, list_rx) = std::sync::mpsc::channel();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(walk_threads.unwrap_or(DEFAULT_WALK_THREADS))
+        .build()?;
+    pool.scope(|scoped| {
+        walk_local_folder(scoped, walk_context, std::path::PathBuf::from(&ext_disk_base_path), vec![], list_tx);
+    });
+    // all senders are dropped when the scope ends, the receiver reads all msgs from the queue
+    for local_folder_list in list_rx {
+        let local_folder_list = local_folder_list?;
+        vec_folders.extend(local_folder_list.vec_folders);
+        vec_files.extend(local_folder_list.vec_files);
+        vec_readonly_files.extend(local_folder_list.vec_readonly_files);
+        vec_special_files.extend(local_folder_list.vec_special_files);
     }
+    let folder_count = vec_folders.len();
+    let file_count = vec_files.len();
+    let readonly_count = vec_readonly_files.len();
+    let special_count = vec_special_files.len();
 
     // region: sort
-    let files_sorted_string = crate::sort_string_lines(&files_string);
-    let folders_sorted_string = crate::sort_string_lines(&folders_string);
-    let readonly_files_sorted_string = crate::sort_string_lines(&readonly_files_string);
+    let files_sorted_string = crate::utils_mod::sort_list(vec_files);
+    let folders_sorted_string = crate::utils_mod::sort_list(vec_folders);
+    let readonly_files_sorted_string = crate::utils_mod::sort_list(vec_readonly_files);
+    let special_files_sorted_string = crate::utils_mod::sort_list(vec_special_files);
     // end region: sort
     file_list_destination_files.write_append_str(&files_sorted_string)?;
     file_list_destination_folders.write_append_str(&folders_sorted_string)?;
     file_list_destination_readonly_files.write_append_str(&readonly_files_sorted_string)?;
+    file_list_destination_special_files.write_append_str(&special_files_sorted_string)?;
 
     println_to_ui_thread_with_thread_name(&ui_tx, format!("Local folder count: {folder_count}"), "L");
     println_to_ui_thread_with_thread_name(&ui_tx, format!("Local file count: {file_count}"), "L");
-    println_to_ui_thread_with_thread_name(
-        &ui_tx,
-        format!("Local readonly count: {}", readonly_files_string.lines().count()),
-        "L",
-    );
+    println_to_ui_thread_with_thread_name(&ui_tx, format!("Local readonly count: {readonly_count}"), "L");
+    println_to_ui_thread_with_thread_name(&ui_tx, format!("Local special files count: {special_count}"), "L");
     println_to_ui_thread_with_thread_name(
         &ui_tx,
         format!("Local duration in seconds: {}", list_local_start.elapsed().as_secs()),
@@ -130,33 +136,319 @@ pub fn list_local(
     Ok(())
 }
 
+/// Shared by all the tasks of the parallel walk of the local disk.
+struct LocalWalkContext<'a> {
+    ext_disk_base_path: &'a str,
+    symlink_policy: SymlinkPolicy,
+    ui_tx: std::sync::mpsc::Sender<(String, ThreadName)>,
+    file_count: std::sync::atomic::AtomicUsize,
+    last_send_ms: std::sync::Mutex<std::time::Instant>,
+}
+
+/// The lines from one local folder, without the base path.
+#[derive(Default)]
+struct LocalFolderList {
+    vec_folders: Vec<String>,
+    vec_files: Vec<String>,
+    vec_readonly_files: Vec<String>,
+    /// `path\treason`, the reason is symlink, followed_symlink, broken_symlink, symlink_loop, socket, fifo, block_device, char_device or special.
+    vec_special_files: Vec<String>,
+}
+
+/// Read one local folder in a task of the threadpool and spawn a new task for every sub-folder.  \
+///
+/// scoped.spawn closure cannot return a Result<>, so the result is sent as inter-thread message.  \
+/// vec_followed contains the canonical targets of the symlinks followed to reach this folder. It protects from loops.  
+fn walk_local_folder<'scope>(
+    scoped: &rayon::Scope<'scope>,
+    walk_context: &'scope LocalWalkContext<'scope>,
+    folder_path: std::path::PathBuf,
+    vec_followed: Vec<std::path::PathBuf>,
+    list_tx: std::sync::mpsc::Sender<Result<LocalFolderList>>,
+) {
+    let mut vec_sub_folders = vec![];
+    let result = list_local_folder(walk_context, &folder_path, &vec_followed, &mut vec_sub_folders);
+    for (sub_folder_path, vec_followed) in vec_sub_folders {
+        let list_tx_move_to_closure = list_tx.clone();
+        scoped.spawn(move |scoped| walk_local_folder(scoped, walk_context, sub_folder_path, vec_followed, list_tx_move_to_closure));
+    }
+    // the receiver is alive until the scope ends, so send cannot fail
+    let _ = list_tx.send(result);
+}
+
+/// List the files and sub-folders of one local folder, not recursive.  \
+///
+/// The sub-folders to walk are pushed to vec_sub_folders with the symlinks followed to reach them.  
+fn list_local_folder(
+    walk_context: &LocalWalkContext,
+    folder_path: &std::path::Path,
+    vec_followed: &[std::path::PathBuf],
+    vec_sub_folders: &mut Vec<(std::path::PathBuf, Vec<std::path::PathBuf>)>,
+) -> Result<LocalFolderList> {
+    let mut local_folder_list = LocalFolderList::default();
+    for entry in std::fs::read_dir(folder_path)? {
+        let entry = entry?;
+        let path = entry.path();
+        let str_path = path.to_str().ok_or_else(|| Error::ErrorFromStr("Error string is not path"))?;
+        // I don't need the "base" folder in this list. The ext_disk_base_path always ends with slash.
+        let str_path_wo_base = str_path.trim_start_matches(walk_context.ext_disk_base_path);
+        // change windows style with backslash to Linux style with neutral crossplatform slash
+        let str_path_wo_base = str_path_wo_base.replace(r#"\"#, "/");
+        // avoid the temp_trash folder, the snapshots and the disk identity
+        if str_path_wo_base.starts_with("0_backup_temp")
+            || str_path_wo_base.starts_with(SNAPSHOTS_FOLDER_NAME)
+            || str_path_wo_base == DISK_IDENTITY_FILE_NAME
+        {
+            continue;
+        }
+        // entry.file_type() is fast, it does not need metadata on most filesystems. It does not follow symlinks.
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            local_folder_list.vec_folders.push(str_path_wo_base);
+            vec_sub_folders.push((path, vec_followed.to_vec()));
+            continue;
+        }
+        // metadata() in wsl/Linux is slow. This is the reason for the parallel walk.
+        let metadata = if file_type.is_symlink() {
+            match walk_context.symlink_policy {
+                SymlinkPolicy::Skip => continue,
+                SymlinkPolicy::Report => {
+                    local_folder_list.vec_special_files.push(format!("{str_path_wo_base}\tsymlink"));
+                    continue;
+                }
+                // std::fs::metadata() follows the symlink
+                SymlinkPolicy::Follow => match std::fs::metadata(&path) {
+                    Ok(metadata) => metadata,
+                    Err(_err) => {
+                        local_folder_list
+                            .vec_special_files
+                            .push(format!("{str_path_wo_base}\tbroken_symlink"));
+                        continue;
+                    }
+                },
+            }
+        } else {
+            match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(_err) => continue,
+            }
+        };
+        if metadata.is_dir() {
+            // only a followed symlink can be a folder here
+            if is_symlink_loop(folder_path, &path, vec_followed) {
+                local_folder_list
+                    .vec_special_files
+                    .push(format!("{str_path_wo_base}\tsymlink_loop"));
+                continue;
+            }
+            let mut vec_followed = vec_followed.to_vec();
+            vec_followed.push(std::fs::canonicalize(&path)?);
+            local_folder_list
+                .vec_special_files
+                .push(format!("{str_path_wo_base}\t{REASON_FOLLOWED_SYMLINK}"));
+            local_folder_list.vec_folders.push(str_path_wo_base);
+            vec_sub_folders.push((path, vec_followed));
+            continue;
+        }
+        if !metadata.is_file() {
+            local_folder_list
+                .vec_special_files
+                .push(format!("{str_path_wo_base}\t{}", special_file_reason(&metadata.file_type())));
+            continue;
+        }
+        if file_type.is_symlink() {
+            local_folder_list
+                .vec_special_files
+                .push(format!("{str_path_wo_base}\t{REASON_FOLLOWED_SYMLINK}"));
+        }
+        // write csv tab delimited
+        // the downloaded revisions don't exist in the remote list
+        if !is_revision_file_name(&str_path_wo_base) {
+            let datetime: DateTime<Utc> = metadata.modified()?.into();
+            if metadata.permissions().readonly() {
+                local_folder_list.vec_readonly_files.push(str_path_wo_base.clone());
+            }
+            local_folder_list.vec_files.push(format!(
+                "{}\t{}\t{}",
+                str_path_wo_base,
+                datetime.format("%Y-%m-%dT%TZ"),
+                metadata.len()
+            ));
+            let file_count = walk_context.file_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+            // don't print every file, because print is slow. Check if 100ms passed, only one thread prints
+            if let Ok(mut last_send_ms) = walk_context.last_send_ms.try_lock() {
+                if last_send_ms.elapsed().as_millis() >= 100 {
+                    println_to_ui_thread_with_thread_name(
+                        &walk_context.ui_tx,
+                        format!("{file_count}: {}", crate::shorten_string(&str_path_wo_base, 80)?),
+                        "L",
+                    );
+                    *last_send_ms = std::time::Instant::now();
+                }
+            }
+        }
+    }
+    Ok(local_folder_list)
+}
+
+/// The symlink to a folder makes a loop.  \
+///
+/// The target is a parent of the folder with the symlink or it was already followed on the way to this folder.  \
+/// If the canonical paths cannot be read, it is treated as a loop and never followed.  
+fn is_symlink_loop(folder_path: &std::path::Path, symlink_path: &std::path::Path, vec_followed: &[std::path::PathBuf]) -> bool {
+    let (Ok(canonical_folder), Ok(canonical_target)) = (std::fs::canonicalize(folder_path), std::fs::canonicalize(symlink_path)) else {
+        return true;
+    };
+    canonical_folder.starts_with(&canonical_target) || vec_followed.contains(&canonical_target)
+}
+
+/// The reason for the list of special files, for everything that is not a file, a folder or a symlink.
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn special_file_reason(file_type: &std::fs::FileType) -> &'static str {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_socket() {
+            return "socket";
+        } else if file_type.is_fifo() {
+            return "fifo";
+        } else if file_type.is_block_device() {
+            return "block_device";
+        } else if file_type.is_char_device() {
+            return "char_device";
+        }
+    }
+    "special"
+}
+
+/// The revision files downloaded by download_revisions have the name `{file_name}.{server_modified}.{rev}.dbxrev`.  \
+///
+/// Only the exact pattern is a revision file. Other files with the extension `.dbxrev` are ordinary files.  
+fn is_revision_file_name(path: &str) -> bool {
+    let Some(path) = path.strip_suffix(".dbxrev") else {
+        return false;
+    };
+    let Some((path, rev)) = path.rsplit_once('.') else {
+        return false;
+    };
+    let Some((file_name, server_modified)) = path.rsplit_once('.') else {
+        return false;
+    };
+    !rev.is_empty()
+        && rev.chars().all(|c| c.is_ascii_alphanumeric())
+        && chrono::NaiveDateTime::parse_from_str(server_modified, "%Y-%m-%d_%H-%M-%S").is_ok()
+        && !file_name.is_empty()
+        && !file_name.ends_with('/')
+}
+
+/// Move the revision files of a file together with the file.  \
+///
+/// list_local hides the revision files, so a compare never moves or trashes them on its own.  \
+/// The revisions `{file_name}.{server_modified}.{rev}.dbxrev` beside move_from get the new file name beside move_to.  
+fn move_revision_files(move_from: &CrossPathBuf, move_to: &CrossPathBuf) -> Result<()> {
+    let path_from = move_from.to_path_buf_current_os();
+    let path_to = move_to.to_path_buf_current_os();
+    let (Some(folder_from), Some(file_name_from), Some(folder_to), Some(file_name_to)) =
+        (path_from.parent(), path_from.file_name(), path_to.parent(), path_to.file_name())
+    else {
+        return Ok(());
+    };
+    if !folder_from.is_dir() {
+        return Ok(());
+    }
+    let prefix = format!("{}.", file_name_from.to_string_lossy());
+    for entry in std::fs::read_dir(folder_from)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // only the revisions of this file: the rest after the file name is exactly `{server_modified}.{rev}.dbxrev`
+        let Some(rest) = name.strip_prefix(&prefix) else {
+            continue;
+        };
+        if rest.matches('.').count() != 2 || !is_revision_file_name(&name) || !entry.file_type()?.is_file() {
+            continue;
+        }
+        std::fs::create_dir_all(folder_to)?;
+        std::fs::rename(entry.path(), folder_to.join(format!("{}.{rest}", file_name_to.to_string_lossy())))?;
+    }
+    Ok(())
+}
+
 /// The backup files must not be readonly to allow copying the modified file from the remote.  \
 ///
+/// The list of readonly files is not emptied. After the sync steps read_only_restore uses it to make the files readonly again.  \
 /// The FileTxt is read+write. It is opened in the bin and not in lib, but it is manipulated only in lib.  
 pub fn read_only_remove(
     ui_tx: std::sync::mpsc::Sender<String>,
     ext_disk_base_path: &CrossPathBuf,
     file_readonly_files: &mut FileTxt,
 ) -> Result<()> {
+    // refuse to change a wrong disk or an empty mount point
+    read_disk_identity(ext_disk_base_path)?;
     let list_readonly_files = file_readonly_files.read_to_string()?;
     for string_path_for_readonly in list_readonly_files.lines() {
         let path_global_path_to_readonly = ext_disk_base_path.join_relative(string_path_for_readonly)?;
         // if path does not exist ignore
         if path_global_path_to_readonly.exists() {
-            let mut perms = path_global_path_to_readonly.to_path_buf_current_os().metadata()?.permissions();
+            let perms = path_global_path_to_readonly.to_path_buf_current_os().metadata()?.permissions();
             if perms.readonly() {
-                // note: on Unix platforms this results in the file being world writable
-                // this is ok for backup
-                #[allow(clippy::permissions_set_readonly_false)]
-                perms.set_readonly(false);
-                match std::fs::set_permissions(path_global_path_to_readonly.to_path_buf_current_os(), perms) {
+                // the permissions are shared with the other hardlinks, so the hardlink must be broken first
+                break_hardlink(ext_disk_base_path, &path_global_path_to_readonly)?;
+                match set_owner_writable(&path_global_path_to_readonly, true) {
                     Ok(_) => println_to_ui_thread(&ui_tx, string_path_for_readonly.to_string()),
                     Err(_err) => println_to_ui_thread(&ui_tx, format!("Error set_permissions readonly: {string_path_for_readonly}")),
                 }
             }
         }
     }
-    file_readonly_files.empty()?;
+    Ok(())
+}
+
+/// Make the files from the list of readonly files readonly again, after the sync steps.  \
+///
+/// The files were readonly when list_local was run. If a file was downloaded again, the new file gets the readonly too.  \
+/// If a file does not exist anymore, it is ignored. The moved files keep their readonly in move_local_files.  
+pub fn read_only_restore(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    file_readonly_files: &mut FileTxt,
+) -> Result<()> {
+    // refuse to change a wrong disk or an empty mount point
+    read_disk_identity(ext_disk_base_path)?;
+    let list_readonly_files = file_readonly_files.read_to_string()?;
+    let mut count_restored = 0;
+    for string_path_for_readonly in list_readonly_files.lines() {
+        let path_global_path_to_readonly = ext_disk_base_path.join_relative(string_path_for_readonly)?;
+        if path_global_path_to_readonly.exists() {
+            let perms = path_global_path_to_readonly.to_path_buf_current_os().metadata()?.permissions();
+            if !perms.readonly() {
+                // the permissions are shared with the other hardlinks, so the hardlink must be broken first
+                break_hardlink(ext_disk_base_path, &path_global_path_to_readonly)?;
+                match set_owner_writable(&path_global_path_to_readonly, false) {
+                    Ok(_) => count_restored += 1,
+                    Err(_err) => println_to_ui_thread(&ui_tx, format!("Error set_permissions readonly: {string_path_for_readonly}")),
+                }
+            }
+        }
+    }
+    println_to_ui_thread(&ui_tx, format!("readonly restored: {count_restored}"));
+    Ok(())
+}
+
+/// Set or clear only the write permission of the owner.  \
+///
+/// On Unix set_readonly(false) makes the file world writable. Here all the other permission bits stay as they are.  
+fn set_owner_writable(path_global: &CrossPathBuf, writable: bool) -> Result<()> {
+    let path = path_global.to_path_buf_current_os();
+    let mut perms = std::fs::metadata(&path)?.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = if writable { perms.mode() | 0o200 } else { perms.mode() & !0o200 };
+        perms.set_mode(mode);
+    }
+    #[cfg(not(unix))]
+    perms.set_readonly(!writable);
+    std::fs::set_permissions(&path, perms)?;
     Ok(())
 }
 
@@ -166,10 +458,13 @@ pub fn change_time_files(
     ext_disk_base_path: &CrossPathBuf,
     file_list_for_change_time_files: &mut FileTxt,
 ) -> Result<()> {
+    verify_plan_header(ext_disk_base_path, file_list_for_change_time_files)?;
     let list_for_change_time_files = file_list_for_change_time_files.read_to_string()?;
     if list_for_change_time_files.is_empty() {
         println_to_ui_thread(&ui_tx, "list_for_change_time_files is empty".to_string());
     } else {
+        // the content is equal to the remote content, so the cache gets the new modified with the remote content_hash
+        let mut content_hash_cache = ContentHashCache::load(ext_disk_base_path)?;
         for line in list_for_change_time_files.lines() {
             let vec_line: Vec<&str> = line.split("\t").collect();
             let path = vec_line[0];
@@ -177,8 +472,14 @@ pub fn change_time_files(
             let path_global_path = ext_disk_base_path.join_relative(path)?;
             println_to_ui_thread(&ui_tx, path_global_path.to_string());
             let modified = filetime::FileTime::from_system_time(humantime::parse_rfc3339(datetime)?);
+            // the modified datetime is shared with the other hardlinks, so the hardlink must be broken first
+            break_hardlink(ext_disk_base_path, &path_global_path)?;
             filetime::set_file_mtime(path_global_path.to_path_buf_current_os(), modified)?;
+            if let Some(content_hash) = vec_line.get(3) {
+                content_hash_cache.insert_from_disk(ext_disk_base_path, path, content_hash)?;
+            }
         }
+        content_hash_cache.save()?;
         file_list_for_change_time_files.empty()?;
     }
     Ok(())
@@ -190,6 +491,7 @@ pub fn create_folders(
     ext_disk_base_path: &CrossPathBuf,
     file_list_for_create_folders: &mut FileTxt,
 ) -> Result<()> {
+    verify_folder_plan_header(ext_disk_base_path, file_list_for_create_folders)?;
     let list_for_create_folders = file_list_for_create_folders.read_to_string()?;
     if list_for_create_folders.is_empty() {
         println_to_ui_thread(&ui_tx, "list_for_create_folders is empty".to_string());
@@ -207,6 +509,227 @@ pub fn create_folders(
     Ok(())
 }
 
+/// Rename files and folders that were renamed in Dropbox only by the case of letters.  \
+///
+/// Folders are renamed first, because the files use the new parent path.  \
+/// The lines are `old_path\tnew_path` and only the last part of the path is renamed.  \
+/// The parent folder is taken from the local old_path, because the case of the remote parent folders is random.
+/// Only the parent folders renamed in this run change it.  \
+/// The rename goes in two steps through a temporary name, because on case-insensitive filesystems
+/// the old and the new name are the same file. If a run stopped between the two steps, the next run finishes the rename.  \
+/// The pending lists for trash and move folders have the destination paths with the old case.
+/// After the rename they are rewritten to the new case, else the next steps would not find the files
+/// on a case-sensitive filesystem and the obsolete files would stay in the backup.  
+pub fn case_rename_local(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    file_list_for_case_rename_folders: &mut FileTxt,
+    file_list_for_case_rename_files: &mut FileTxt,
+    file_list_for_trash_files: &mut FileTxt,
+    file_list_for_trash_folders: &mut FileTxt,
+    file_list_for_move_folders: &mut FileTxt,
+) -> Result<()> {
+    verify_plan_header(ext_disk_base_path, file_list_for_case_rename_files)?;
+    verify_folder_plan_header(ext_disk_base_path, file_list_for_case_rename_folders)?;
+    // local old_path -> local new_path of the renamed files and folders
+    let mut renamed_paths: std::collections::HashMap<String, String> = std::collections::HashMap::new();
+    // the paths are rewritten also if the rename returns an error, for the lines already renamed
+    let result_folders = case_rename_from_list(&ui_tx, ext_disk_base_path, file_list_for_case_rename_folders, &mut renamed_paths);
+    let result_files = match result_folders {
+        Ok(()) => case_rename_from_list(&ui_tx, ext_disk_base_path, file_list_for_case_rename_files, &mut renamed_paths),
+        Err(_) => Ok(()),
+    };
+
+    if !renamed_paths.is_empty() {
+        for file_list in [file_list_for_trash_files, file_list_for_trash_folders, file_list_for_move_folders] {
+            let string_list = file_list.read_to_string()?;
+            let string_list_rewritten = rewrite_paths_after_case_rename(&string_list, &renamed_paths);
+            if string_list_rewritten != string_list {
+                file_list.replace_atomic(&string_list_rewritten)?;
+            }
+        }
+    }
+    result_folders?;
+    result_files?;
+    Ok(())
+}
+
+/// Rewrite the path in the first column of every line to the new case.  
+fn rewrite_paths_after_case_rename(string_list: &str, renamed_paths: &std::collections::HashMap<String, String>) -> String {
+    string_list
+        .lines()
+        .map(|line| {
+            let (path, rest) = line.split_once("\t").map_or((line, None), |(path, rest)| (path, Some(rest)));
+            let new_path = path_after_case_rename(path, renamed_paths);
+            match rest {
+                Some(rest) => format!("{new_path}\t{rest}"),
+                None => new_path,
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// The local path after the case renames.  \
+///
+/// The path itself or the nearest renamed parent folder is found in renamed_paths and this part of the path is replaced.  
+fn path_after_case_rename(path: &str, renamed_paths: &std::collections::HashMap<String, String>) -> String {
+    // the path itself and then every parent folder, the nearest first
+    let mut old_prefix = path;
+    loop {
+        if let Some(new_prefix) = renamed_paths.get(old_prefix) {
+            return format!("{new_prefix}{}", &path[old_prefix.len()..]);
+        }
+        match old_prefix.rsplit_once('/') {
+            Some((parent, _name)) => old_prefix = parent,
+            None => return path.to_string(),
+        }
+    }
+}
+
+/// Rename from one list and write the actual situation to disk.  
+fn case_rename_from_list(
+    ui_tx: &std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    file_list_for_case_rename: &mut FileTxt,
+    renamed_paths: &mut std::collections::HashMap<String, String>,
+) -> Result<()> {
+    let list_for_case_rename = file_list_for_case_rename.read_to_string()?;
+    let mut vec_list_for_case_rename: Vec<&str> = list_for_case_rename.lines().collect();
+    let mut set_renamed: std::collections::HashSet<&str> = std::collections::HashSet::new();
+    let result = case_rename_internal(
+        ui_tx,
+        ext_disk_base_path,
+        &vec_list_for_case_rename,
+        renamed_paths,
+        &mut set_renamed,
+    );
+    // remove the renamed lines with one pass, also in case of error
+    vec_list_for_case_rename.retain(|line| !set_renamed.contains(line));
+    match result {
+        Ok(()) => {
+            // in case all is ok, write actual situation to disk and continue
+            file_list_for_case_rename.empty()?;
+            file_list_for_case_rename.write_append_str(&vec_list_for_case_rename.join("\n"))?;
+        }
+        Err(err) => {
+            // also in case of error, write the actual situation to disk and return error
+            file_list_for_case_rename.empty()?;
+            file_list_for_case_rename.write_append_str(&vec_list_for_case_rename.join("\n"))?;
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+// internal because of catching errors
+// The renamed lines are collected in set_renamed.
+fn case_rename_internal<'a>(
+    ui_tx: &std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    vec_list_for_case_rename: &[&'a str],
+    renamed_paths: &mut std::collections::HashMap<String, String>,
+    set_renamed: &mut std::collections::HashSet<&'a str>,
+) -> Result<()> {
+    for line_for_case_rename in vec_list_for_case_rename.iter() {
+        let (old_path, new_path) = line_for_case_rename
+            .split_once("\t")
+            .ok_or(Error::ErrorFromStr("the line in list_for_case_rename must have 2 columns"))?;
+        // the local parent folder, only the parent folders renamed in this run have the new case
+        let old_path_local = path_after_case_rename(old_path, renamed_paths);
+        let local_parent = old_path_local.rsplit_once('/').map_or("", |(parent, _name)| parent);
+        let sibling_path = |name: &str| {
+            if local_parent.is_empty() {
+                name.to_string()
+            } else {
+                format!("{local_parent}/{name}")
+            }
+        };
+        let new_path_local = sibling_path(file_name_from_path(new_path));
+        let path_temp =
+            ext_disk_base_path.join_relative(&sibling_path(&format!("{}.case_rename_temp", file_name_from_path(&old_path_local))))?;
+        let path_old = ext_disk_base_path.join_relative(&old_path_local)?;
+        let path_new = ext_disk_base_path.join_relative(&new_path_local)?;
+        if path_old.exists() {
+            println_to_ui_thread(ui_tx, format!("case rename {}  ->  {}", &path_old, &path_new));
+            std::fs::rename(path_old.to_path_buf_current_os(), path_temp.to_path_buf_current_os())?;
+            std::fs::rename(path_temp.to_path_buf_current_os(), path_new.to_path_buf_current_os())?;
+        } else if path_temp.exists() {
+            // the last run stopped between the two steps
+            println_to_ui_thread(ui_tx, format!("finish case rename {}  ->  {}", &path_temp, &path_new));
+            std::fs::rename(path_temp.to_path_buf_current_os(), path_new.to_path_buf_current_os())?;
+        }
+        // if neither exists ignore, probably it was renamed earlier
+        if path_new.is_file() {
+            move_revision_files(&path_old, &path_new)?;
+        }
+        if path_new.exists() {
+            renamed_paths.insert(old_path.to_string(), new_path_local);
+        }
+        set_renamed.insert(line_for_case_rename);
+    }
+    Ok(())
+}
+
+/// Move or rename whole folders from list_for_move_folders with a single rename.  \
+///
+/// The lines are `old_folder\tnew_folder\tfiles\tbytes`.  \
+/// If the old folder does not exist or the new folder already exists, the line is skipped.
+/// The files will be found again by the next compare.  
+pub fn move_local_folders(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    file_list_for_move_folders: &mut FileTxt,
+) -> Result<()> {
+    verify_plan_header(ext_disk_base_path, file_list_for_move_folders)?;
+    let list_for_move_folders = file_list_for_move_folders.read_to_string()?;
+    let mut vec_list_for_move_folders: Vec<&str> = list_for_move_folders.lines().collect();
+    let mut set_moved: std::collections::HashSet<&str> = std::collections::HashSet::new();
+    let result = move_local_folders_internal(&ui_tx, ext_disk_base_path, &vec_list_for_move_folders, &mut set_moved);
+    // remove the moved lines with one pass, also in case of error
+    vec_list_for_move_folders.retain(|line| !set_moved.contains(line));
+    match result {
+        Ok(()) => {
+            // in case all is ok, write actual situation to disk and continue
+            file_list_for_move_folders.empty()?;
+            file_list_for_move_folders.write_append_str(&vec_list_for_move_folders.join("\n"))?;
+        }
+        Err(err) => {
+            // also in case of error, write the actual situation to disk and return error
+            file_list_for_move_folders.empty()?;
+            file_list_for_move_folders.write_append_str(&vec_list_for_move_folders.join("\n"))?;
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+// internal because of catching errors
+fn move_local_folders_internal<'a>(
+    ui_tx: &std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    vec_list_for_move_folders: &[&'a str],
+    set_moved: &mut std::collections::HashSet<&'a str>,
+) -> Result<()> {
+    for line_for_move_folders in vec_list_for_move_folders.iter() {
+        let vec_line: Vec<&str> = line_for_move_folders.split("\t").collect();
+        if vec_line.len() < 2 {
+            return Err(Error::ErrorFromStr("the line in list_for_move_folders must have 4 columns"));
+        }
+        let path_move_from = ext_disk_base_path.join_relative(vec_line[0])?;
+        let path_move_to = ext_disk_base_path.join_relative(vec_line[1])?;
+        if !path_move_from.exists() || path_move_to.exists() {
+            println_to_ui_thread(ui_tx, format!("skip move folder {}  ->  {}", &path_move_from, &path_move_to));
+        } else {
+            println_to_ui_thread(ui_tx, format!("move folder {}  ->  {}", &path_move_from, &path_move_to));
+            path_move_to.create_dir_all_for_file()?;
+            std::fs::rename(path_move_from.to_path_buf_current_os(), path_move_to.to_path_buf_current_os())?;
+        }
+        set_moved.insert(line_for_move_folders);
+    }
+    Ok(())
+}
+
 /// Files are often moved.  \
 ///
 /// After compare, the same file (with different path or name) will be in the list_for_trash_files and in the list_for_download.  \
@@ -218,7 +741,9 @@ pub fn move_local_files(
     ext_disk_base_path: &CrossPathBuf,
     file_list_for_trash_files: &mut FileTxt,
     file_list_for_download: &mut FileTxt,
+    compare_options: &CompareOptions,
 ) -> Result<()> {
+    verify_plan_header(ext_disk_base_path, file_list_for_download)?;
     let list_for_trash_files = file_list_for_trash_files.read_to_string()?;
     let list_for_download = file_list_for_download.read_to_string()?;
     let mut vec_list_for_trash_files: Vec<&str> = list_for_trash_files.lines().collect();
@@ -229,6 +754,7 @@ pub fn move_local_files(
         ext_disk_base_path,
         &mut vec_list_for_trash_files,
         &mut vec_list_for_download,
+        compare_options,
     ) {
         Ok(()) => {
             // in case all is ok, write actual situation to disk and continue
@@ -261,13 +787,21 @@ pub fn rename_local_files(
     ext_disk_base_path: &CrossPathBuf,
     file_list_for_trash_files: &mut FileTxt,
     file_list_for_download: &mut FileTxt,
+    compare_options: &CompareOptions,
 ) -> Result<()> {
+    verify_plan_header(ext_disk_base_path, file_list_for_download)?;
     let list_for_trash_files = file_list_for_trash_files.read_to_string()?;
     let list_for_download = file_list_for_download.read_to_string()?;
     let mut vec_list_for_trash: Vec<&str> = list_for_trash_files.lines().collect();
     let mut vec_list_for_download: Vec<&str> = list_for_download.lines().collect();
 
-    match rename_local_files_internal_by_hash(ui_tx, ext_disk_base_path, &mut vec_list_for_trash, &mut vec_list_for_download) {
+    match rename_local_files_internal_by_hash(
+        ui_tx,
+        ext_disk_base_path,
+        &mut vec_list_for_trash,
+        &mut vec_list_for_download,
+        compare_options,
+    ) {
         Ok(()) => {
             // in case all is ok, write actual situation to disk
             file_list_for_trash_files.empty()?;
@@ -287,166 +821,473 @@ pub fn rename_local_files(
     Ok(())
 }
 
-// internal because of catching errors
-fn move_local_files_internal_by_name(
+/// Files are often duplicated: the same photo in two albums or a copied project folder.  \
+///
+/// The files in list_source_files, that are not in list_for_download, are already equal on the local disk.  \
+/// Their content_hash is known from the remote list, so it is easy to make an index of the local content.  \
+/// For every line in list_for_download, if the content_hash exists in the index, the local file is copied instead of downloaded.  \
+/// With use_hardlink the file is hard-linked instead of copied, but only if the modified datetime is also equal,
+/// because hard-linked files share the same modified datetime.  \
+/// Remove also the lines in file list_for_download.  
+pub fn copy_local_files(
     ui_tx: std::sync::mpsc::Sender<String>,
     ext_disk_base_path: &CrossPathBuf,
-    vec_list_for_trash_files: &mut Vec<&str>,
-    vec_list_for_download: &mut Vec<&str>,
+    file_list_source_files: &FileTxt,
+    file_list_for_download: &mut FileTxt,
+    use_hardlink: bool,
 ) -> Result<()> {
-    let mut count_moved = 0;
-    // it is not possible to remove an element when iterating a Vec
-    // I will iterate by a clone, so I can remove an element in the original Vec
-    let vec_list_for_trash_clone = vec_list_for_trash_files.clone();
-    let vec_list_for_download_clone = vec_list_for_download.clone();
-    let mut last_send_ms = std::time::Instant::now();
+    verify_plan_header(ext_disk_base_path, file_list_for_download)?;
+    let list_source_files = file_list_source_files.read_to_string()?;
+    let list_for_download = file_list_for_download.read_to_string()?;
+    let mut vec_list_for_download: Vec<&str> = list_for_download.lines().collect();
 
-    for line_for_trash_files in vec_list_for_trash_clone.iter() {
-        let split_line_for_trash: Vec<&str> = line_for_trash_files.split("\t").collect();
-        let string_path_for_trash_files = split_line_for_trash[0];
-        let path_global_to_trash_files = ext_disk_base_path.join_relative(string_path_for_trash_files)?;
-        // if path does not exist ignore, probably it has moved or trashed earlier
-        if path_global_to_trash_files.exists() {
-            let modified_for_trash_files = split_line_for_trash[1];
-            let size_for_trash_files = split_line_for_trash[2];
-            let file_name_for_trash_files = path_global_to_trash_files.file_name()?;
-
-            // search in list_for_download for possible candidates
-            // first try exact match with name, date, size because it is fast
-            for line_for_download in vec_list_for_download_clone.iter() {
-                // Every 1 second write a dot, to see it still works like a progress bar
-                if last_send_ms.elapsed().as_millis() >= 1000 {
-                    // this is a special character fpr a progress bar
-                    println_to_ui_thread(&ui_tx, ".".to_string());
-
-                    last_send_ms = std::time::Instant::now();
-                }
-                let split_line_for_download: Vec<&str> = line_for_download.split("\t").collect();
-                let string_path_for_download = split_line_for_download[0];
-                let modified_for_download = split_line_for_download[1];
-                let size_for_download = split_line_for_download[2];
-                let path_global_to_download = ext_disk_base_path.join_relative(string_path_for_download)?;
-                let file_name_for_download = path_global_to_download.file_name()?;
-
-                let modified_for_trash_files: DateTime<Utc> = DateTime::parse_from_rfc3339(modified_for_trash_files)?.into();
-                let modified_for_download: DateTime<Utc> = DateTime::parse_from_rfc3339(modified_for_download)?.into();
-                if chrono::Duration::from(modified_for_trash_files - modified_for_download).abs() < chrono::Duration::seconds(2)
-                    && size_for_trash_files == size_for_download
-                    && file_name_for_trash_files == file_name_for_download
-                {
-                    move_internal(&ui_tx, &path_global_to_trash_files, &path_global_to_download)?;
-                    // remove the lines from the original mut Vec
-                    vec_list_for_trash_files.retain(|line| line != line_for_trash_files);
-                    vec_list_for_download.retain(|line| line != line_for_download);
-
-                    count_moved += 1;
-                    break;
-                }
-            }
+    let mut set_copied: std::collections::HashSet<&str> = std::collections::HashSet::new();
+    let result = copy_local_files_internal_by_hash(
+        ui_tx,
+        ext_disk_base_path,
+        &list_source_files,
+        &vec_list_for_download,
+        use_hardlink,
+        &mut set_copied,
+    );
+    // remove the copied lines with one pass, also in case of error
+    vec_list_for_download.retain(|line| !set_copied.contains(line));
+    match result {
+        Ok(()) => {
+            // in case all is ok, write actual situation to disk
+            file_list_for_download.empty()?;
+            file_list_for_download.write_append_str(&vec_list_for_download.join("\n"))?;
+        }
+        Err(err) => {
+            // also in case of error, write the actual situation to disk
+            file_list_for_download.empty()?;
+            file_list_for_download.write_append_str(&vec_list_for_download.join("\n"))?;
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+// Internal because of catching errors.
+// The copied lines are collected in set_copied.
+fn copy_local_files_internal_by_hash<'a>(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    list_source_files: &str,
+    vec_list_for_download: &[&'a str],
+    use_hardlink: bool,
+    set_copied: &mut std::collections::HashSet<&'a str>,
+) -> Result<()> {
+    let vec_copies = match_local_files_by_content_hash(ext_disk_base_path, list_source_files, vec_list_for_download)?;
+    let mut count_copied = 0;
+    let mut content_hash_cache = ContentHashCache::load(ext_disk_base_path)?;
+    // the previous version of an overwritten file is moved in this folder, like in download
+    let versions_folder_name = chrono::Local::now().format("versions_%Y-%m-%d_%H-%M-%S").to_string();
+    for (index_for_download, line_candidate) in vec_copies.iter() {
+        let line_for_download = vec_list_for_download[*index_for_download];
+        let split_line_for_download: Vec<&str> = line_for_download.split("\t").collect();
+        let string_path_for_download = split_line_for_download[0];
+        let modified_for_download = split_line_for_download[1];
+        let split_line_candidate: Vec<&str> = line_candidate.split("\t").collect();
+        let path_global_candidate = ext_disk_base_path.join_relative(split_line_candidate[0])?;
+        let path_global_for_download = ext_disk_base_path.join_relative(string_path_for_download)?;
+        move_to_versions(ext_disk_base_path, &versions_folder_name, string_path_for_download)?;
+        path_global_for_download.create_dir_all_for_file()?;
+
+        let modified_candidate: DateTime<Utc> = DateTime::parse_from_rfc3339(split_line_candidate[1])?.into();
+        let modified_for_download_dt: DateTime<Utc> = DateTime::parse_from_rfc3339(modified_for_download)?.into();
+        let is_hardlinked = use_hardlink
+            && modified_candidate == modified_for_download_dt
+            && std::fs::hard_link(
+                path_global_candidate.to_path_buf_current_os(),
+                path_global_for_download.to_path_buf_current_os(),
+            )
+            .is_ok();
+        if is_hardlinked {
+            println_to_ui_thread(
+                &ui_tx,
+                format!("hardlink {}  ->  {}", &path_global_candidate, &path_global_for_download),
+            );
+        } else {
+            println_to_ui_thread(
+                &ui_tx,
+                format!("copy {}  ->  {}", &path_global_candidate, &path_global_for_download),
+            );
+            std::fs::copy(
+                path_global_candidate.to_path_buf_current_os(),
+                path_global_for_download.to_path_buf_current_os(),
+            )?;
+            let modified = filetime::FileTime::from_system_time(humantime::parse_rfc3339(modified_for_download)?);
+            filetime::set_file_mtime(path_global_for_download.to_path_buf_current_os(), modified)?;
         }
+        content_hash_cache.insert_from_disk(ext_disk_base_path, string_path_for_download, split_line_for_download[3])?;
+        set_copied.insert(line_for_download);
+        count_copied += 1;
     }
 
-    println_to_ui_thread(&ui_tx, format!("moved by name: {}", count_moved));
+    content_hash_cache.save()?;
+    println_to_ui_thread(&ui_tx, format!("copied by hash: {}", count_copied));
     Ok(())
 }
 
+/// Find a local file with the same content_hash for the lines in list_for_download.  \
+///
+/// The local files are the lines of list_source_files, that are not in list_for_download.  \
+/// The local file must still exist with the expected size.  \
+/// Returns the index in list_for_download and the line of the local file. It is used by copy_local_files and by the dry-run.  
+pub(crate) fn match_local_files_by_content_hash<'a>(
+    ext_disk_base_path: &CrossPathBuf,
+    list_source_files: &'a str,
+    vec_list_for_download: &[&str],
+) -> Result<Vec<(IndexForDownload, &'a str)>> {
+    // the paths for download are not yet equal on the local disk
+    let set_path_for_download: std::collections::HashSet<String> = vec_list_for_download
+        .iter()
+        .map(|line| path_key(line.split("\t").next().unwrap_or_default()))
+        .collect();
+    // index of local files by content_hash
+    let mut index_by_hash: std::collections::HashMap<&str, Vec<&str>> = std::collections::HashMap::new();
+    for line_source in list_source_files.lines() {
+        let split_line_source: Vec<&str> = line_source.split("\t").collect();
+        if split_line_source.len() >= 4 && !set_path_for_download.contains(&path_key(split_line_source[0])) {
+            index_by_hash.entry(split_line_source[3]).or_default().push(line_source);
+        }
+    }
+
+    let mut vec_copies = vec![];
+    for (index_for_download, line_for_download) in vec_list_for_download.iter().enumerate() {
+        let split_line_for_download: Vec<&str> = line_for_download.split("\t").collect();
+        let size_for_download = split_line_for_download[2];
+        let Some(vec_candidates) = split_line_for_download
+            .get(3)
+            .and_then(|content_hash| index_by_hash.get(content_hash))
+        else {
+            continue;
+        };
+        for line_candidate in vec_candidates.iter() {
+            let path_global_candidate = ext_disk_base_path.join_relative(line_candidate.split("\t").next().unwrap_or_default())?;
+            if path_global_candidate.exists()
+                && std::fs::metadata(path_global_candidate.to_path_buf_current_os())?.len().to_string() == size_for_download
+            {
+                vec_copies.push((index_for_download, *line_candidate));
+                break;
+            }
+        }
+    }
+    Ok(vec_copies)
+}
+
+// type alias for better expressing coder intention,
+// but programmatically identical to the underlying type
+pub(crate) type IndexForTrash = usize;
+pub(crate) type IndexForDownload = usize;
+/// The line for download with the parsed modified datetime and the remote content_hash.
+type DownloadCandidate<'a> = (IndexForDownload, DateTime<Utc>, &'a str);
+/// The lines for download indexed by file name and size.
+type IndexByNameAndSize<'a> = std::collections::HashMap<(String, &'a str), Vec<DownloadCandidate<'a>>>;
+/// The lines for download indexed by size and modified bucket.
+type IndexBySizeAndBucket<'a> = std::collections::HashMap<(&'a str, i64), Vec<DownloadCandidate<'a>>>;
+
+// internal because of catching errors
+fn move_local_files_internal_by_name(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    vec_list_for_trash_files: &mut Vec<&str>,
+    vec_list_for_download: &mut Vec<&str>,
+    compare_options: &CompareOptions,
+) -> Result<()> {
+    let timestamp_tolerance = compare_options.timestamp_tolerance(ext_disk_base_path)?;
+    let mut content_hash_cache = ContentHashCache::load(ext_disk_base_path)?;
+    let vec_pairs = match_local_files_by_name(
+        ext_disk_base_path,
+        &mut content_hash_cache,
+        vec_list_for_trash_files,
+        vec_list_for_download,
+        timestamp_tolerance,
+        compare_options.hash_only,
+    )?;
+    content_hash_cache.save()?;
+    let mut vec_moved = vec![];
+    let result = move_matched_pairs(
+        &ui_tx,
+        ext_disk_base_path,
+        vec_list_for_trash_files,
+        vec_list_for_download,
+        &vec_pairs,
+        &mut vec_moved,
+    );
+    let vec_moved_for_download: Vec<&str> = vec_moved.iter().map(|pair| vec_list_for_download[pair.1]).collect();
+    let string_by_reason = count_by_reason(&vec_moved_for_download, REASON_COLUMN_FOR_DOWNLOAD);
+    // remove the lines from the original mut Vec, also in case of error
+    remove_moved_lines(vec_list_for_trash_files, vec_list_for_download, &vec_moved);
+    println_to_ui_thread(&ui_tx, format!("moved by name: {} ({string_by_reason})", vec_moved.len()));
+    result
+}
+
 // Internal because of catching errors.
 fn rename_local_files_internal_by_hash(
     ui_tx: std::sync::mpsc::Sender<String>,
     ext_disk_base_path: &CrossPathBuf,
     vec_list_for_trash_files: &mut Vec<&str>,
     vec_list_for_download: &mut Vec<&str>,
+    compare_options: &CompareOptions,
 ) -> Result<()> {
-    let mut count_moved = 0;
-    // it is not possible to remove an element when iterating a Vec
-    // I will iterate by a clone, so I can remove an element in the original Vec
-    let vec_list_for_trash_clone = vec_list_for_trash_files.clone();
-    let vec_list_for_download_clone = vec_list_for_download.clone();
-    let mut last_send_ms = std::time::Instant::now();
+    let timestamp_tolerance = compare_options.timestamp_tolerance(ext_disk_base_path)?;
+    let mut content_hash_cache = ContentHashCache::load(ext_disk_base_path)?;
+    let vec_pairs = match_local_files_by_hash(
+        &ui_tx,
+        ext_disk_base_path,
+        &mut content_hash_cache,
+        vec_list_for_trash_files,
+        vec_list_for_download,
+        timestamp_tolerance,
+        compare_options.hash_only,
+    )?;
+    content_hash_cache.save()?;
+    let mut vec_moved = vec![];
+    let result = move_matched_pairs(
+        &ui_tx,
+        ext_disk_base_path,
+        vec_list_for_trash_files,
+        vec_list_for_download,
+        &vec_pairs,
+        &mut vec_moved,
+    );
+    let vec_moved_for_download: Vec<&str> = vec_moved.iter().map(|pair| vec_list_for_download[pair.1]).collect();
+    let string_by_reason = count_by_reason(&vec_moved_for_download, REASON_COLUMN_FOR_DOWNLOAD);
+    // remove the lines from the original mut Vec, also in case of error
+    remove_moved_lines(vec_list_for_trash_files, vec_list_for_download, &vec_moved);
+    println_to_ui_thread(&ui_tx, format!("Renamed: {} ({string_by_reason})", vec_moved.len()));
+    result
+}
 
-    for line_for_trash_files in vec_list_for_trash_clone.iter() {
-        let split_line_for_trash: Vec<&str> = line_for_trash_files.split("\t").collect();
-        let string_path_for_trash_files = split_line_for_trash[0];
-        let path_global_to_trash_files = ext_disk_base_path.join_relative(string_path_for_trash_files)?;
-        // if path does not exist ignore, probably it eas moved or trashed earlier
-        if path_global_to_trash_files.exists() {
-            let modified_for_trash_files = split_line_for_trash[1];
-            let size_for_trash_files = split_line_for_trash[2];
-
-            for line_for_download in vec_list_for_download_clone.iter() {
-                // Every 1 second write a dot, to see it still works like a progress bar
-                if last_send_ms.elapsed().as_millis() >= 1000 {
-                    // this is a special character fpr a progress bar
-                    println_to_ui_thread(&ui_tx, ".".to_string());
-
-                    last_send_ms = std::time::Instant::now();
-                }
-                let split_line_for_download: Vec<&str> = line_for_download.split("\t").collect();
-                let string_path_for_download = split_line_for_download[0];
-                let modified_for_download = split_line_for_download[1];
-                let size_for_download = split_line_for_download[2];
-                let remote_content_hash = split_line_for_download[3];
-                let path_global_to_download = ext_disk_base_path.join_relative(string_path_for_download)?;
-
-                let modified_for_trash_files: DateTime<Utc> = DateTime::parse_from_rfc3339(modified_for_trash_files)?.into();
-                let modified_for_download: DateTime<Utc> = DateTime::parse_from_rfc3339(modified_for_download)?.into();
-                if chrono::Duration::from(modified_for_trash_files - modified_for_download).abs() < chrono::Duration::seconds(2)
-                    && size_for_trash_files == size_for_download
-                {
-                    // same size and date. Let's check the content_hash to be sure.
-                    let local_content_hash = format!(
-                        "{:x}",
-                        DropboxContentHasher::hash_file(path_global_to_trash_files.to_path_buf_current_os())?
-                    );
+/// Find pairs of trash and download lines with the same file name, size and modified.  \
+///
+/// The lines for download are indexed by (file name, size), so the search for candidates is constant-time.  \
+/// The modified datetime is parsed only once for every line. Every line is used in max one pair.  \
+/// In hash-only mode the modified datetime is ignored and the content_hash must be equal.  
+pub(crate) fn match_local_files_by_name(
+    ext_disk_base_path: &CrossPathBuf,
+    content_hash_cache: &mut ContentHashCache,
+    vec_list_for_trash_files: &[&str],
+    vec_list_for_download: &[&str],
+    timestamp_tolerance: chrono::Duration,
+    hash_only: bool,
+) -> Result<Vec<(IndexForTrash, IndexForDownload)>> {
+    let mut index_by_name_and_size: IndexByNameAndSize = std::collections::HashMap::new();
+    for (index_for_download, line_for_download) in vec_list_for_download.iter().enumerate() {
+        let split_line_for_download: Vec<&str> = line_for_download.split("\t").collect();
+        let file_name = path_key(file_name_from_path(split_line_for_download[0]));
+        let modified_for_download: DateTime<Utc> = DateTime::parse_from_rfc3339(split_line_for_download[1])?.into();
+        index_by_name_and_size
+            .entry((file_name, split_line_for_download[2]))
+            .or_default()
+            .push((index_for_download, modified_for_download, split_line_for_download[3]));
+    }
 
-                    if local_content_hash == remote_content_hash {
-                        move_internal(&ui_tx, &path_global_to_trash_files, &path_global_to_download)?;
-                        // remove the lines from the original mut Vec
-                        vec_list_for_trash_files.retain(|line| line != line_for_trash_files);
-                        vec_list_for_download.retain(|line| line != line_for_download);
-                        count_moved += 1;
-                        break;
+    let mut vec_pairs = vec![];
+    let mut set_used_for_download = std::collections::HashSet::new();
+    for (index_for_trash, line_for_trash_files) in vec_list_for_trash_files.iter().enumerate() {
+        let split_line_for_trash: Vec<&str> = line_for_trash_files.split("\t").collect();
+        let file_name = path_key(file_name_from_path(split_line_for_trash[0]));
+        let Some(vec_candidates) = index_by_name_and_size.get(&(file_name, split_line_for_trash[2])) else {
+            continue;
+        };
+        // if path does not exist ignore, probably it has moved or trashed earlier
+        if !ext_disk_base_path.join_relative(split_line_for_trash[0])?.exists() {
+            continue;
+        }
+        let modified_for_trash_files: DateTime<Utc> = DateTime::parse_from_rfc3339(split_line_for_trash[1])?.into();
+        let local_content_hash = if hash_only {
+            content_hash_cache.content_hash(ext_disk_base_path, split_line_for_trash[0])?
+        } else {
+            String::new()
+        };
+        let candidate = vec_candidates
+            .iter()
+            .find(|(index_for_download, modified_for_download, remote_content_hash)| {
+                !set_used_for_download.contains(index_for_download)
+                    && if hash_only {
+                        *remote_content_hash == local_content_hash
+                    } else {
+                        (modified_for_trash_files - *modified_for_download).abs() <= timestamp_tolerance
                     }
-                }
-            }
+            });
+        if let Some((index_for_download, _modified, _content_hash)) = candidate {
+            set_used_for_download.insert(*index_for_download);
+            vec_pairs.push((index_for_trash, *index_for_download));
         }
     }
+    Ok(vec_pairs)
+}
 
-    println_to_ui_thread(&ui_tx, format!("Renamed: {}", count_moved));
+/// Find pairs of trash and download lines with the same size, modified and content_hash.  \
+///
+/// The lines for download are indexed by (size, modified bucket of the timestamp tolerance), so the search for candidates is constant-time.  \
+/// The neighbor buckets are also searched, because of the tolerance.  \
+/// In hash-only mode the modified datetime is ignored and the lines are indexed only by size.  \
+/// The local content_hash is calculated only once for every trash file and only if there is a candidate and it is not in the cache.  
+pub(crate) fn match_local_files_by_hash(
+    ui_tx: &std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    content_hash_cache: &mut ContentHashCache,
+    vec_list_for_trash_files: &[&str],
+    vec_list_for_download: &[&str],
+    timestamp_tolerance: chrono::Duration,
+    hash_only: bool,
+) -> Result<Vec<(IndexForTrash, IndexForDownload)>> {
+    let bucket_seconds = timestamp_tolerance.num_seconds().max(1);
+    let bucket_of = |modified: &DateTime<Utc>| {
+        if hash_only {
+            0
+        } else {
+            modified.timestamp().div_euclid(bucket_seconds)
+        }
+    };
+    let mut index_by_size_and_bucket: IndexBySizeAndBucket = std::collections::HashMap::new();
+    for (index_for_download, line_for_download) in vec_list_for_download.iter().enumerate() {
+        let split_line_for_download: Vec<&str> = line_for_download.split("\t").collect();
+        let modified_for_download: DateTime<Utc> = DateTime::parse_from_rfc3339(split_line_for_download[1])?.into();
+        index_by_size_and_bucket
+            .entry((split_line_for_download[2], bucket_of(&modified_for_download)))
+            .or_default()
+            .push((index_for_download, modified_for_download, split_line_for_download[3]));
+    }
+
+    let mut vec_pairs = vec![];
+    let mut set_used_for_download = std::collections::HashSet::new();
+    let mut last_send_ms = std::time::Instant::now();
+    for (index_for_trash, line_for_trash_files) in vec_list_for_trash_files.iter().enumerate() {
+        // Every 1 second write a dot, to see it still works like a progress bar
+        if last_send_ms.elapsed().as_millis() >= 1000 {
+            // this is a special character fpr a progress bar
+            println_to_ui_thread(ui_tx, ".".to_string());
+            last_send_ms = std::time::Instant::now();
+        }
+        let split_line_for_trash: Vec<&str> = line_for_trash_files.split("\t").collect();
+        let size_for_trash_files = split_line_for_trash[2];
+        let modified_for_trash_files: DateTime<Utc> = DateTime::parse_from_rfc3339(split_line_for_trash[1])?.into();
+        let bucket = bucket_of(&modified_for_trash_files);
+        let vec_buckets = if hash_only {
+            vec![bucket]
+        } else {
+            vec![bucket - 1, bucket, bucket + 1]
+        };
+        let vec_candidates: Vec<&DownloadCandidate> = vec_buckets
+            .iter()
+            .filter_map(|bucket| index_by_size_and_bucket.get(&(size_for_trash_files, *bucket)))
+            .flatten()
+            .filter(|(index_for_download, modified_for_download, _content_hash)| {
+                !set_used_for_download.contains(index_for_download)
+                    && (hash_only || (modified_for_trash_files - *modified_for_download).abs() <= timestamp_tolerance)
+            })
+            .collect();
+        if vec_candidates.is_empty() {
+            continue;
+        }
+        let path_global_to_trash_files = ext_disk_base_path.join_relative(split_line_for_trash[0])?;
+        // if path does not exist ignore, probably it was moved or trashed earlier
+        if !path_global_to_trash_files.exists() {
+            continue;
+        }
+        // same size and date. Let's check the content_hash to be sure.
+        let local_content_hash = content_hash_cache.content_hash(ext_disk_base_path, split_line_for_trash[0])?;
+        let candidate = vec_candidates
+            .iter()
+            .find(|(_index_for_download, _modified, remote_content_hash)| *remote_content_hash == local_content_hash);
+        if let Some((index_for_download, _modified, _content_hash)) = candidate {
+            set_used_for_download.insert(*index_for_download);
+            vec_pairs.push((index_for_trash, *index_for_download));
+        }
+    }
+    Ok(vec_pairs)
+}
+
+/// Returns the last part of the path after the last slash.  
+fn file_name_from_path(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Move the files from the matched pairs and remember the moved pairs.  \
+///
+/// The previous version of a file in the place of the moved file is moved to the versions folder, like in download.  
+fn move_matched_pairs(
+    ui_tx: &std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    vec_list_for_trash_files: &[&str],
+    vec_list_for_download: &[&str],
+    vec_pairs: &[(IndexForTrash, IndexForDownload)],
+    vec_moved: &mut Vec<(IndexForTrash, IndexForDownload)>,
+) -> Result<()> {
+    let versions_folder_name = chrono::Local::now().format("versions_%Y-%m-%d_%H-%M-%S").to_string();
+    for (index_for_trash, index_for_download) in vec_pairs.iter() {
+        let string_path_for_trash_files = vec_list_for_trash_files[*index_for_trash].split("\t").next().unwrap_or_default();
+        let string_path_for_download = vec_list_for_download[*index_for_download].split("\t").next().unwrap_or_default();
+        let path_global_to_trash_files = ext_disk_base_path.join_relative(string_path_for_trash_files)?;
+        move_internal(
+            ui_tx,
+            ext_disk_base_path,
+            &versions_folder_name,
+            &path_global_to_trash_files,
+            string_path_for_download,
+        )?;
+        vec_moved.push((*index_for_trash, *index_for_download));
+    }
     Ok(())
 }
 
+/// Remove the moved lines from list_for_trash_files and list_for_download in one pass.  
+pub(crate) fn remove_moved_lines(
+    vec_list_for_trash_files: &mut Vec<&str>,
+    vec_list_for_download: &mut Vec<&str>,
+    vec_moved: &[(IndexForTrash, IndexForDownload)],
+) {
+    let set_moved_for_trash: std::collections::HashSet<IndexForTrash> = vec_moved.iter().map(|pair| pair.0).collect();
+    let set_moved_for_download: std::collections::HashSet<IndexForDownload> = vec_moved.iter().map(|pair| pair.1).collect();
+    let mut index = 0;
+    vec_list_for_trash_files.retain(|_line| {
+        index += 1;
+        !set_moved_for_trash.contains(&(index - 1))
+    });
+    let mut index = 0;
+    vec_list_for_download.retain(|_line| {
+        index += 1;
+        !set_moved_for_download.contains(&(index - 1))
+    });
+}
+
 /// Internal code to move file.  
 fn move_internal(
     ui_tx: &std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    versions_folder_name: &str,
     path_global_to_trash: &CrossPathBuf,
-    path_global_for_download: &CrossPathBuf,
+    string_path_for_download: &str,
 ) -> Result<()> {
     let move_from = path_global_to_trash;
-    let move_to = path_global_for_download;
+    let move_to = &ext_disk_base_path.join_relative(string_path_for_download)?;
     println_to_ui_thread(ui_tx, format!("move {}  ->  {}", &move_from, &move_to));
     move_to.create_dir_all_for_file()?;
+    // A rename changes only the directory entry, so it never changes a snapshot that shares the file with a hardlink.
+    // The previous version of the file is kept, the rename would silently replace it. Also a readonly file is moved, never changed.
     if move_to.exists() {
-        let mut perms = std::fs::metadata(move_to.to_path_buf_current_os())?.permissions();
-        if perms.readonly() {
-            // note: on Unix platforms this results in the file being world writable
-            // this is ok for backup
-            #[allow(clippy::permissions_set_readonly_false)]
-            perms.set_readonly(false);
-            std::fs::set_permissions(move_to.to_path_buf_current_os(), perms)?;
-        }
+        move_to_versions(ext_disk_base_path, versions_folder_name, string_path_for_download)?;
     }
-    if move_from.exists() {
-        let mut perms = std::fs::metadata(move_from.to_path_buf_current_os())?.permissions();
-        if perms.readonly() {
-            // note: on Unix platforms this results in the file being world writable
-            // this is ok for backup
-            #[allow(clippy::permissions_set_readonly_false)]
-            perms.set_readonly(false);
-            std::fs::set_permissions(move_from.to_path_buf_current_os(), perms)?;
-        }
+    // On Unix rename does not need write permission on the file. Clearing readonly would change the permissions shared with the snapshots,
+    // and if the rename fails, the file would stay writable.
+    #[cfg(not(unix))]
+    let is_move_from_readonly = move_from.exists() && std::fs::metadata(move_from.to_path_buf_current_os())?.permissions().readonly();
+    #[cfg(not(unix))]
+    if is_move_from_readonly {
+        set_owner_writable(move_from, true)?;
     }
     std::fs::rename(move_from.to_path_buf_current_os(), move_to.to_path_buf_current_os())?;
+    move_revision_files(move_from, move_to)?;
+    // the moved file keeps the original readonly
+    #[cfg(not(unix))]
+    if is_move_from_readonly {
+        set_owner_writable(move_to, false)?;
+    }
     Ok(())
 }
 
@@ -458,10 +1299,15 @@ pub fn trash_files(
     ext_disk_base_path: &CrossPathBuf,
     file_list_for_trash_files: &mut FileTxt,
 ) -> Result<()> {
+    verify_plan_header(ext_disk_base_path, file_list_for_trash_files)?;
     let list_for_trash_files = file_list_for_trash_files.read_to_string()?;
     let mut vec_list_for_trash_files: Vec<&str> = list_for_trash_files.lines().collect();
 
-    match trash_files_internal(ui_tx.clone(), ext_disk_base_path, &mut vec_list_for_trash_files) {
+    let mut set_trashed: std::collections::HashSet<&str> = std::collections::HashSet::new();
+    let result = trash_files_internal(ui_tx.clone(), ext_disk_base_path, &vec_list_for_trash_files, &mut set_trashed);
+    // remove the trashed lines with one pass, also in case of error
+    vec_list_for_trash_files.retain(|line| !set_trashed.contains(line));
+    match result {
         Ok(()) => {
             // in case all is ok, write actual situation to disk and continue
             file_list_for_trash_files.empty()?;
@@ -477,19 +1323,19 @@ pub fn trash_files(
     Ok(())
 }
 
-/// Internal function.  
-fn trash_files_internal(
+/// Internal function. The trashed lines are collected in set_trashed.  
+fn trash_files_internal<'a>(
     ui_tx: std::sync::mpsc::Sender<String>,
     ext_disk_base_path: &CrossPathBuf,
-    vec_list_for_trash_files: &mut Vec<&str>,
+    vec_list_for_trash_files: &[&'a str],
+    set_trashed: &mut std::collections::HashSet<&'a str>,
 ) -> Result<()> {
-    let vec_list_for_trash_clone = vec_list_for_trash_files.clone();
     let now_string = chrono::Local::now().format("trash_%Y-%m-%d_%H-%M-%S").to_string();
     // the trash folder will be inside DropboxBackup because of permissions
     let base_trash_path = ext_disk_base_path.join_relative("0_backup_temp")?.join_relative(&now_string)?;
     base_trash_path.create_dir_all()?;
     //move the files in the same directory structure
-    for line_path_for_trash_files in vec_list_for_trash_clone.iter() {
+    for line_path_for_trash_files in vec_list_for_trash_files.iter() {
         let line: Vec<&str> = line_path_for_trash_files.split("\t").collect();
         let string_path_for_trash_files = line[0];
         let path_move_from = ext_disk_base_path.join_relative(string_path_for_trash_files)?;
@@ -499,8 +1345,9 @@ fn trash_files_internal(
             println_to_ui_thread(&ui_tx, format!("{}", path_move_from));
             path_move_to.create_dir_all_for_file()?;
             std::fs::rename(path_move_from.to_path_buf_current_os(), path_move_to.to_path_buf_current_os())?;
+            move_revision_files(&path_move_from, &path_move_to)?;
         }
-        vec_list_for_trash_files.retain(|line| line != line_path_for_trash_files);
+        set_trashed.insert(line_path_for_trash_files);
     }
     Ok(())
 }
@@ -511,13 +1358,29 @@ pub fn trash_folders(
     ext_disk_base_path: &CrossPathBuf,
     file_list_for_trash_folders: &mut FileTxt,
 ) -> Result<()> {
+    verify_folder_plan_header(ext_disk_base_path, file_list_for_trash_folders)?;
     let list_for_trash_folders = file_list_for_trash_folders.read_to_string()?;
     let mut vec_list_for_trash_folders: Vec<&str> = list_for_trash_folders.lines().collect();
-    let vec_list_for_trash_clone = vec_list_for_trash_folders.clone();
+    let mut set_trashed: std::collections::HashSet<&str> = std::collections::HashSet::new();
+    let result = trash_folders_internal(ui_tx, ext_disk_base_path, &vec_list_for_trash_folders, &mut set_trashed);
+    // remove the trashed lines with one pass, also in case of error
+    vec_list_for_trash_folders.retain(|line| !set_trashed.contains(line));
+    file_list_for_trash_folders.empty()?;
+    file_list_for_trash_folders.write_append_str(&vec_list_for_trash_folders.join("\n"))?;
+    result
+}
+
+/// Internal function. The trashed lines are collected in set_trashed.  
+fn trash_folders_internal<'a>(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    vec_list_for_trash_folders: &[&'a str],
+    set_trashed: &mut std::collections::HashSet<&'a str>,
+) -> Result<()> {
     let now_string = chrono::Local::now().format("trash_%Y-%m-%d_%H-%M-%S").to_string();
     let base_trash_path_folders = ext_disk_base_path.join_relative("0_backup_temp")?.join_relative(&now_string)?;
     base_trash_path_folders.create_dir_all()?;
-    for string_path_for_trash_folders in vec_list_for_trash_clone.iter() {
+    for string_path_for_trash_folders in vec_list_for_trash_folders.iter() {
         let path_move_from = ext_disk_base_path.join_relative(string_path_for_trash_folders)?;
         // move to trash if file exists. Nothing if it does not exist, maybe is deleted when moved or in a move to trash before.
         if path_move_from.exists() {
@@ -526,10 +1389,272 @@ pub fn trash_folders(
             path_move_to.create_dir_all_for_file()?;
             std::fs::rename(path_move_from.to_path_buf_current_os(), path_move_to.to_path_buf_current_os())?;
         }
-        vec_list_for_trash_folders.retain(|line| line != string_path_for_trash_folders);
+        set_trashed.insert(string_path_for_trash_folders);
     }
-    file_list_for_trash_folders.empty()?;
-    file_list_for_trash_folders.write_append_str(&vec_list_for_trash_folders.join("\n"))?;
+    Ok(())
+}
 
+/// Move the previous version of a file into the versions folder, before it is overwritten by a newer download.  \
+///
+/// The versions folder is similar to the trash folder: `0_backup_temp/versions_YYYY-MM-DD_HH-MM-SS`.  \
+/// It keeps the same directory structure as the backup. Nothing happens if the file does not exist.  
+pub fn move_to_versions(ext_disk_base_path: &CrossPathBuf, versions_folder_name: &str, string_path: &str) -> Result<()> {
+    let path_move_from = ext_disk_base_path.join_relative(string_path)?;
+    if path_move_from.exists() {
+        let path_move_to = ext_disk_base_path
+            .join_relative("0_backup_temp")?
+            .join_relative(versions_folder_name)?
+            .join_relative(string_path)?;
+        path_move_to.create_dir_all_for_file()?;
+        std::fs::rename(path_move_from.to_path_buf_current_os(), path_move_to.to_path_buf_current_os())?;
+    }
     Ok(())
 }
+
+/// Delete old versions from the `0_backup_temp/versions_*` folders.  \
+///
+/// A version is kept if it is one of the last `keep_last` versions of the same file or if it is newer than `keep_days`.  \
+/// If both parameters are None, nothing is deleted.  
+pub fn purge_versions(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    keep_last: Option<usize>,
+    keep_days: Option<i64>,
+) -> Result<()> {
+    // refuse to change a wrong disk or an empty mount point
+    read_disk_identity(ext_disk_base_path)?;
+    if keep_last.is_none() && keep_days.is_none() {
+        println_to_ui_thread(&ui_tx, "no retention policy for versions".to_string());
+        return Ok(());
+    }
+    let base_temp_path = ext_disk_base_path.join_relative("0_backup_temp")?;
+    if !base_temp_path.exists() {
+        return Ok(());
+    }
+    let now = chrono::Local::now().naive_local();
+
+    // for every file path collect all versions: (datetime of version folder, global path to version)
+    let mut versions_by_path: std::collections::HashMap<String, Vec<(chrono::NaiveDateTime, std::path::PathBuf)>> =
+        std::collections::HashMap::new();
+    let mut vec_versions_folders = vec![];
+    for entry in std::fs::read_dir(base_temp_path.to_path_buf_current_os())? {
+        let entry = entry?;
+        let folder_name = entry.file_name().to_string_lossy().to_string();
+        if !entry.file_type()?.is_dir() || !folder_name.starts_with("versions_") {
+            continue;
+        }
+        let Ok(datetime) = chrono::NaiveDateTime::parse_from_str(&folder_name, "versions_%Y-%m-%d_%H-%M-%S") else {
+            println_to_ui_thread(&ui_tx, format!("Error versions folder name is not a date: {folder_name}"));
+            continue;
+        };
+        let folder_path = entry.path();
+        for file_entry in walkdir::WalkDir::new(&folder_path) {
+            let file_entry = file_entry?;
+            if file_entry.file_type().is_file() {
+                let string_path = file_entry
+                    .path()
+                    .strip_prefix(&folder_path)
+                    .map_err(|_err| Error::ErrorFromStr("Bug: version must be inside versions folder"))?
+                    .to_string_lossy()
+                    .replace(r#"\"#, "/");
+                versions_by_path
+                    .entry(string_path)
+                    .or_default()
+                    .push((datetime, file_entry.path().to_path_buf()));
+            }
+        }
+        vec_versions_folders.push(folder_path);
+    }
+
+    let mut count_deleted = 0;
+    for (string_path, mut vec_versions) in versions_by_path {
+        // newest first
+        vec_versions.sort_by_key(|(datetime, _path_version)| std::cmp::Reverse(*datetime));
+        for (index, (datetime, path_version)) in vec_versions.iter().enumerate() {
+            let keep_by_count = keep_last.is_some_and(|keep_last| index < keep_last);
+            let keep_by_age = keep_days.is_some_and(|keep_days| now - *datetime < chrono::Duration::days(keep_days));
+            if !keep_by_count && !keep_by_age {
+                match std::fs::remove_file(path_version) {
+                    Ok(()) => {
+                        println_to_ui_thread(&ui_tx, format!("{} {string_path}", datetime.format("%Y-%m-%d_%H-%M-%S")));
+                        count_deleted += 1;
+                    }
+                    Err(err) => println_to_ui_thread(&ui_tx, format!("Error remove version {string_path}: {err}")),
+                }
+            }
+        }
+    }
+
+    // remove empty folders, the children first. remove_dir() returns error if the folder is not empty, that is ok.
+    for folder_path in vec_versions_folders.iter() {
+        for entry in walkdir::WalkDir::new(folder_path).contents_first(true).into_iter().flatten() {
+            if entry.file_type().is_dir() {
+                let _ = std::fs::remove_dir(entry.path());
+            }
+        }
+    }
+    println_to_ui_thread(&ui_tx, format!("versions deleted: {count_deleted}"));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_revision_file_name_only_exact_pattern() {
+        assert!(is_revision_file_name("folder/report.txt.2024-03-01_10-20-30.a1c10ce0dd78.dbxrev"));
+        assert!(is_revision_file_name("report.2024-03-01_10-20-30.015f9e8e3a2b.dbxrev"));
+        assert!(!is_revision_file_name("folder/notes.dbxrev"));
+        assert!(!is_revision_file_name("folder/report.txt.a1c10ce0dd78.dbxrev"));
+        assert!(!is_revision_file_name("folder/report.txt.2024-13-01_10-20-30.a1c10ce0dd78.dbxrev"));
+        assert!(!is_revision_file_name("folder/.2024-03-01_10-20-30.a1c10ce0dd78.dbxrev"));
+        assert!(!is_revision_file_name("folder/report.txt.2024-03-01_10-20-30.a1c10ce0dd78"));
+    }
+
+    #[test]
+    fn move_keeps_the_previous_version_of_the_destination() {
+        let (ui_tx, _ui_rx) = std::sync::mpsc::channel();
+        let base_path = crate::utils_mod::new_test_folder("move_keeps_previous_version");
+        std::fs::create_dir_all(base_path.join_relative("old").unwrap().to_path_buf_current_os()).unwrap();
+        std::fs::create_dir_all(base_path.join_relative("new").unwrap().to_path_buf_current_os()).unwrap();
+        std::fs::write(base_path.join_relative("old/a.txt").unwrap().to_path_buf_current_os(), "moved").unwrap();
+        std::fs::write(base_path.join_relative("new/a.txt").unwrap().to_path_buf_current_os(), "previous").unwrap();
+        let vec_list_for_trash_files = vec!["old/a.txt\t2024-01-01T00:00:00Z\t5"];
+        let vec_list_for_download = vec!["new/a.txt\t2024-01-01T00:00:00Z\t5"];
+        let mut vec_moved = vec![];
+        move_matched_pairs(
+            &ui_tx,
+            &base_path,
+            &vec_list_for_trash_files,
+            &vec_list_for_download,
+            &[(0, 0)],
+            &mut vec_moved,
+        )
+        .unwrap();
+        assert_eq!(vec_moved, vec![(0, 0)]);
+        assert!(!base_path.join_relative("old/a.txt").unwrap().exists());
+        assert_eq!(base_path.join_relative("new/a.txt").unwrap().read_to_string().unwrap(), "moved");
+        let base_temp_path = base_path.join_relative("0_backup_temp").unwrap();
+        let versions_folder = std::fs::read_dir(base_temp_path.to_path_buf_current_os())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        assert!(versions_folder.file_name().unwrap().to_string_lossy().starts_with("versions_"));
+        assert_eq!(std::fs::read_to_string(versions_folder.join("new/a.txt")).unwrap(), "previous");
+    }
+
+    #[test]
+    fn case_rename_uses_the_local_parent_and_finishes_a_stopped_rename() {
+        let (ui_tx, _ui_rx) = std::sync::mpsc::channel();
+        let base_path = crate::utils_mod::new_test_folder("case_rename_local_parent");
+        for string_path in ["Docs/Report.txt", "Docs/Note.txt.case_rename_temp", "Photos/A.jpg", "Photos/B.jpg"] {
+            let path_global = base_path.join_relative(string_path).unwrap();
+            path_global.create_dir_all_for_file().unwrap();
+            std::fs::write(path_global.to_path_buf_current_os(), string_path).unwrap();
+        }
+        let mut renamed_paths = std::collections::HashMap::new();
+        let mut set_renamed = std::collections::HashSet::new();
+        let vec_list_for_case_rename_folders = vec!["Photos\tphotos"];
+        case_rename_internal(
+            &ui_tx,
+            &base_path,
+            &vec_list_for_case_rename_folders,
+            &mut renamed_paths,
+            &mut set_renamed,
+        )
+        .unwrap();
+        // the remote parent DOCS has another case than the local parent Docs
+        let vec_list_for_case_rename_files = vec![
+            "Docs/Report.txt\tDOCS/report.txt",
+            "Docs/Note.txt\tdocs/note.txt",
+            "Photos/A.jpg\tphotos/a.jpg",
+        ];
+        case_rename_internal(
+            &ui_tx,
+            &base_path,
+            &vec_list_for_case_rename_files,
+            &mut renamed_paths,
+            &mut set_renamed,
+        )
+        .unwrap();
+        assert_eq!(set_renamed.len(), 4);
+        for string_path in ["Docs/report.txt", "Docs/note.txt", "photos/a.jpg", "photos/B.jpg"] {
+            assert!(base_path.join_relative(string_path).unwrap().exists(), "{string_path}");
+        }
+        assert_eq!(
+            rewrite_paths_after_case_rename("Photos/B.jpg\t2024-01-01T00:00:00Z\t12\nDocs/Report.txt", &renamed_paths),
+            "photos/B.jpg\t2024-01-01T00:00:00Z\t12\nDocs/report.txt"
+        );
+    }
+
+    #[test]
+    fn revisions_are_moved_and_trashed_with_their_file() {
+        let (ui_tx, _ui_rx) = std::sync::mpsc::channel();
+        let base_path = crate::utils_mod::new_test_folder("revisions_move_with_file");
+        let rev = "2024-03-01_10-20-30.a1c10ce0dd78.dbxrev";
+        for string_path in [
+            "old/a.txt".to_string(),
+            format!("old/a.txt.{rev}"),
+            format!("old/a.txt.b.{rev}"),
+            "old/c.txt".to_string(),
+            format!("old/c.txt.{rev}"),
+        ] {
+            let path_global = base_path.join_relative(&string_path).unwrap();
+            path_global.create_dir_all_for_file().unwrap();
+            std::fs::write(path_global.to_path_buf_current_os(), &string_path).unwrap();
+        }
+        let vec_moved = &mut vec![];
+        move_matched_pairs(
+            &ui_tx,
+            &base_path,
+            &["old/a.txt\t2024-01-01T00:00:00Z\t5"],
+            &["new/a.txt\t2024-01-01T00:00:00Z\t5"],
+            &[(0, 0)],
+            vec_moved,
+        )
+        .unwrap();
+        assert!(base_path.join_relative(&format!("new/a.txt.{rev}")).unwrap().exists());
+        assert!(!base_path.join_relative(&format!("old/a.txt.{rev}")).unwrap().exists());
+        // the revision of the file "a.txt.b" is not a revision of "a.txt"
+        assert!(base_path.join_relative(&format!("old/a.txt.b.{rev}")).unwrap().exists());
+
+        let (ui_tx, _ui_rx) = std::sync::mpsc::channel();
+        let vec_list_for_trash_files = vec!["old/c.txt\t2024-01-01T00:00:00Z\t5"];
+        let mut set_trashed = std::collections::HashSet::new();
+        trash_files_internal(ui_tx, &base_path, &vec_list_for_trash_files, &mut set_trashed).unwrap();
+        assert_eq!(set_trashed.len(), 1);
+        assert!(!base_path.join_relative(&format!("old/c.txt.{rev}")).unwrap().exists());
+        let trash_folder = std::fs::read_dir(base_path.join_relative("0_backup_temp").unwrap().to_path_buf_current_os())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.file_name().unwrap().to_string_lossy().starts_with("trash_"))
+            .unwrap();
+        assert!(trash_folder.join(format!("old/c.txt.{rev}")).exists());
+    }
+
+    #[test]
+    fn hardlinked_duplicate_is_broken_also_without_snapshots() {
+        let base_path = crate::utils_mod::new_test_folder("break_hardlink_without_snapshots");
+        let path_original = base_path.join_relative("a.txt").unwrap();
+        let path_duplicate = base_path.join_relative("copy/a.txt").unwrap();
+        std::fs::write(path_original.to_path_buf_current_os(), "same content").unwrap();
+        path_duplicate.create_dir_all_for_file().unwrap();
+        std::fs::hard_link(path_original.to_path_buf_current_os(), path_duplicate.to_path_buf_current_os()).unwrap();
+        let modified_original = std::fs::metadata(path_original.to_path_buf_current_os())
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert!(!base_path.join_relative(SNAPSHOTS_FOLDER_NAME).unwrap().exists());
+
+        assert!(break_hardlink(&base_path, &path_duplicate).unwrap());
+        filetime::set_file_mtime(path_duplicate.to_path_buf_current_os(), filetime::FileTime::from_unix_time(0, 0)).unwrap();
+        set_owner_writable(&path_duplicate, false).unwrap();
+        let metadata_original = std::fs::metadata(path_original.to_path_buf_current_os()).unwrap();
+        assert_eq!(metadata_original.modified().unwrap(), modified_original);
+        assert!(!metadata_original.permissions().readonly());
+        assert!(!break_hardlink(&base_path, &path_original).unwrap());
+    }
+}
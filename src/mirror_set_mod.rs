@@ -0,0 +1,309 @@
+// mirror_set_mod.rs
+
+//! Mirror set: one remote list for two or more backup disks.
+//!
+//! Every disk has its own AppConfig with its own destination lists and plan lists, only the list of source files is shared.
+//! The remote is listed only once. Every attached disk is listed locally and compared with the same remote list.
+//! The first attached disk is synced as usual with download_from_list.
+//! For the other attached disks, copy_from_mirror copies the files from the first disk instead of downloading them again.
+//! Only the files that are not yet on the first disk are left for download.
+//! A disk that is not attached is skipped. When it is attached again, the next compare finds all the differences.
+
+use crossplatform_path::CrossPathBuf;
+
+use crate::compare_mod::{compare_files, filesystem_timestamp_tolerance, is_modified_within_tolerance, CompareOptions};
+use crate::content_hash_cache_mod::ContentHashCache;
+use crate::error_mod::{Error, Result};
+use crate::plan_mod::verify_plan_header;
+use crate::utils_mod::{path_key, println_to_ui_thread};
+use crate::{AppConfig, FileTxt};
+
+/// The disks of the mirror set. Every disk has its own AppConfig.
+#[derive(Debug)]
+pub struct MirrorSet {
+    vec_disks: Vec<&'static AppConfig>,
+}
+
+impl MirrorSet {
+    /// All disks must share the list of source files and must have their own lists and plan lists.  \
+    ///
+    /// The lists of all disks can be in the same folder with different file names.
+    pub fn new(vec_disks: Vec<&'static AppConfig>) -> Result<Self> {
+        let Some(first_disk) = vec_disks.first() else {
+            return Err(Error::ErrorFromStr("mirror set must have at least one disk"));
+        };
+        for disk in vec_disks.iter() {
+            if disk.path_list_source_files.as_str() != first_disk.path_list_source_files.as_str() {
+                return Err(Error::ErrorFromString(format!(
+                    "all disks in mirror set must share the list of source files: {}",
+                    disk.path_list_source_files
+                )));
+            }
+        }
+        // the lists can be in one folder, but every disk needs its own files. The plan headers have the disk uuid in the file name.
+        let mut set_disk_lists: std::collections::HashSet<&str> = std::collections::HashSet::new();
+        for disk in vec_disks.iter() {
+            for path_list in disk_lists(disk) {
+                if !set_disk_lists.insert(path_list.as_str()) {
+                    return Err(Error::ErrorFromString(format!(
+                        "every disk in mirror set must have its own lists and plan lists: {path_list}"
+                    )));
+                }
+            }
+        }
+        Ok(MirrorSet { vec_disks })
+    }
+
+    /// Returns the attached disks with their base path, in the order of the mirror set.  \
+    ///
+    /// A disk is attached if its base path exists.
+    pub fn attached_disks(&self, ui_tx: &std::sync::mpsc::Sender<String>) -> Result<Vec<(&'static AppConfig, CrossPathBuf)>> {
+        let mut vec_attached = vec![];
+        for disk in self.vec_disks.iter() {
+            let base_path = FileTxt::open_for_read(&disk.path_list_ext_disk_base_path)?.read_to_string()?;
+            let base_path = CrossPathBuf::new(&base_path)?;
+            if base_path.exists() {
+                vec_attached.push((*disk, base_path));
+            } else {
+                println_to_ui_thread(ui_tx, format!("disk is not attached, it will be synced next time: {base_path}"));
+            }
+        }
+        Ok(vec_attached)
+    }
+}
+
+/// The lists of one disk: everything except the lists of the remote source.
+fn disk_lists(app_config: &AppConfig) -> [&CrossPathBuf; 16] {
+    [
+        &app_config.path_list_ext_disk_base_path,
+        &app_config.path_list_destination_files,
+        &app_config.path_list_destination_folders,
+        &app_config.path_list_readonly_files,
+        &app_config.path_list_destination_special_files,
+        &app_config.path_list_for_download,
+        &app_config.path_list_for_trash_files,
+        &app_config.path_list_just_downloaded,
+        &app_config.path_list_for_trash_folders,
+        &app_config.path_list_for_create_folders,
+        &app_config.path_list_for_change_time_files,
+        &app_config.path_list_for_case_rename_files,
+        &app_config.path_list_for_case_rename_folders,
+        &app_config.path_list_for_move_folders,
+        &app_config.path_list_scrub_progress,
+        &app_config.path_list_scrub_corrupted,
+    ]
+}
+
+/// Compare the remote list with the local list of every attached disk.  \
+///
+/// Every disk gets its own plan lists and plan header. The local list of every disk must be already made with list_local.  \
+/// Every disk must have the disk identity of this account_id and include roots.
+pub fn compare_mirror_set(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    mirror_set: &MirrorSet,
+    compare_options: &CompareOptions,
+    account_id: &str,
+    include_roots: &[String],
+) -> Result<()> {
+    let vec_attached = mirror_set.attached_disks(&ui_tx)?;
+    if vec_attached.is_empty() {
+        return Err(Error::ErrorFromStr("no disk of the mirror set is attached"));
+    }
+    for (app_config, base_path) in vec_attached.iter() {
+        println_to_ui_thread(&ui_tx, format!("compare mirror disk: {base_path}"));
+        compare_files(ui_tx.clone(), app_config, compare_options, account_id, include_roots)?;
+    }
+    Ok(())
+}
+
+/// Copy files from the source disk instead of downloading them again to the destination disk.  \
+///
+/// The source disk must be already synced. A file is copied only if it is not waiting for download on the source disk
+/// and if it has the same size and modified datetime as in list_for_download of the destination disk.  \
+/// If the source disk has a valid content_hash in the cache, it must also be equal.  \
+/// The previous version on the destination disk is moved to the versions folder, like in download.  \
+/// Remove also the lines in list_for_download of the destination disk, the rest must be downloaded.
+pub fn copy_from_mirror(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    source_app_config: &'static AppConfig,
+    destination_app_config: &'static AppConfig,
+) -> Result<()> {
+    let source_base_path = FileTxt::open_for_read(&source_app_config.path_list_ext_disk_base_path)?.read_to_string()?;
+    let source_base_path = CrossPathBuf::new(&source_base_path)?;
+    let destination_base_path = FileTxt::open_for_read(&destination_app_config.path_list_ext_disk_base_path)?.read_to_string()?;
+    let destination_base_path = CrossPathBuf::new(&destination_base_path)?;
+    let source_list_for_download = FileTxt::open_for_read(&source_app_config.path_list_for_download)?.read_to_string()?;
+
+    let mut file_list_for_download = FileTxt::open_for_read_and_write(&destination_app_config.path_list_for_download)?;
+    verify_plan_header(&destination_base_path, &file_list_for_download)?;
+    let list_for_download = file_list_for_download.read_to_string()?;
+    let mut vec_list_for_download: Vec<&str> = list_for_download.lines().collect();
+
+    let mut set_copied: std::collections::HashSet<&str> = std::collections::HashSet::new();
+    let result = copy_from_mirror_internal(
+        &ui_tx,
+        &source_base_path,
+        &destination_base_path,
+        &source_list_for_download,
+        &vec_list_for_download,
+        &mut set_copied,
+    );
+    // remove the copied lines with one pass, also in case of error
+    vec_list_for_download.retain(|line| !set_copied.contains(line));
+    println_to_ui_thread(&ui_tx, format!("left for download: {}", vec_list_for_download.len()));
+    match result {
+        Ok(()) => {
+            // in case all is ok, write actual situation to disk
+            file_list_for_download.empty()?;
+            file_list_for_download.write_append_str(&vec_list_for_download.join("\n"))?;
+        }
+        Err(err) => {
+            // also in case of error, write the actual situation to disk
+            file_list_for_download.empty()?;
+            file_list_for_download.write_append_str(&vec_list_for_download.join("\n"))?;
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+// Internal because of catching errors.
+// The copied lines are collected in set_copied.
+fn copy_from_mirror_internal<'a>(
+    ui_tx: &std::sync::mpsc::Sender<String>,
+    source_base_path: &CrossPathBuf,
+    destination_base_path: &CrossPathBuf,
+    source_list_for_download: &str,
+    vec_list_for_download: &[&'a str],
+    set_copied: &mut std::collections::HashSet<&'a str>,
+) -> Result<()> {
+    // these files are not yet equal to the remote on the source disk
+    let set_source_for_download: std::collections::HashSet<String> = source_list_for_download
+        .lines()
+        .map(|line| path_key(line.split("\t").next().unwrap_or_default()))
+        .collect();
+    let source_content_hash_cache = ContentHashCache::load(source_base_path)?;
+    let mut content_hash_cache = ContentHashCache::load(destination_base_path)?;
+    // the modified datetime of the source disk is rounded by its filesystem
+    let timestamp_tolerance = filesystem_timestamp_tolerance(source_base_path)?;
+    // the previous version of an overwritten file is moved in this folder, like in download
+    let versions_folder_name = chrono::Local::now().format("versions_%Y-%m-%d_%H-%M-%S").to_string();
+    let mut count_copied = 0;
+    let mut bytes_copied = 0u64;
+    for line_for_download in vec_list_for_download.iter() {
+        let split_line_for_download: Vec<&str> = line_for_download.split("\t").collect();
+        let string_path_for_download = split_line_for_download[0];
+        let modified_for_download = split_line_for_download[1];
+        let size_for_download = split_line_for_download[2].parse::<u64>().unwrap_or_default();
+        let content_hash_for_download = split_line_for_download.get(3).copied().unwrap_or_default();
+        if set_source_for_download.contains(&path_key(string_path_for_download)) {
+            continue;
+        }
+        let path_global_source = source_base_path.join_relative(string_path_for_download)?;
+        if !path_global_source.exists() {
+            continue;
+        }
+        let (source_modified, source_size) = crate::content_hash_cache_mod::local_modified_and_size(&path_global_source)?;
+        if source_size != size_for_download || !is_modified_within_tolerance(&source_modified, modified_for_download, timestamp_tolerance)?
+        {
+            continue;
+        }
+        if let Some(source_content_hash) = source_content_hash_cache.get_valid(string_path_for_download, &source_modified, source_size) {
+            if source_content_hash != content_hash_for_download {
+                continue;
+            }
+        }
+
+        let path_global_for_download = destination_base_path.join_relative(string_path_for_download)?;
+        println_to_ui_thread(ui_tx, format!("copy {}  ->  {}", &path_global_source, &path_global_for_download));
+        // never leave an incomplete file in place of the backup file
+        let mut path_temp = path_global_for_download.to_path_buf_current_os().into_os_string();
+        path_temp.push(".mirror_temp");
+        path_global_for_download.create_dir_all_for_file()?;
+        std::fs::copy(path_global_source.to_path_buf_current_os(), &path_temp)?;
+        crate::local_disk_mod::move_to_versions(destination_base_path, &versions_folder_name, string_path_for_download)?;
+        std::fs::rename(&path_temp, path_global_for_download.to_path_buf_current_os())?;
+        // the modified datetime is changed only after the rename, like in download
+        let modified = filetime::FileTime::from_system_time(humantime::parse_rfc3339(modified_for_download)?);
+        filetime::set_file_mtime(path_global_for_download.to_path_buf_current_os(), modified)?;
+        if !content_hash_for_download.is_empty() {
+            content_hash_cache.insert_from_disk(destination_base_path, string_path_for_download, content_hash_for_download)?;
+        }
+        set_copied.insert(line_for_download);
+        count_copied += 1;
+        bytes_copied += size_for_download;
+    }
+
+    content_hash_cache.save()?;
+    println_to_ui_thread(ui_tx, format!("copied from mirror: {count_copied}, {bytes_copied} bytes"));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_identity_mod::initialize_disk_identity;
+    use crate::utils_mod::new_test_folder;
+
+    /// AppConfig of one disk with the lists in the shared folder, the file names end with the disk name.
+    fn disk_config(path_folder_lists: &CrossPathBuf, disk_name: &str, base_path: &CrossPathBuf) -> &'static AppConfig {
+        let disk_list = |name: &str| path_folder_lists.join_relative(&format!("{name}_{disk_name}.csv")).unwrap();
+        let app_config = AppConfig {
+            path_list_ext_disk_base_path: disk_list("list_ext_disk_base_path"),
+            path_list_destination_files: disk_list("list_destination_files"),
+            path_list_destination_folders: disk_list("list_destination_folders"),
+            path_list_readonly_files: disk_list("list_destination_readonly_files"),
+            path_list_destination_special_files: disk_list("list_destination_special_files"),
+            path_list_for_download: disk_list("list_for_download"),
+            path_list_for_trash_files: disk_list("list_for_trash_files"),
+            path_list_just_downloaded: disk_list("list_just_downloaded"),
+            path_list_for_trash_folders: disk_list("list_for_trash_folders"),
+            path_list_for_create_folders: disk_list("list_for_create_folders"),
+            path_list_for_change_time_files: disk_list("list_for_change_time_files"),
+            path_list_for_case_rename_files: disk_list("list_for_case_rename_files"),
+            path_list_for_case_rename_folders: disk_list("list_for_case_rename_folders"),
+            path_list_for_move_folders: disk_list("list_for_move_folders"),
+            path_list_scrub_progress: disk_list("list_scrub_progress"),
+            path_list_scrub_corrupted: disk_list("list_scrub_corrupted"),
+            ..AppConfig::new(path_folder_lists).unwrap()
+        };
+        std::fs::write(app_config.path_list_ext_disk_base_path.to_path_buf_current_os(), base_path.as_str()).unwrap();
+        std::fs::write(app_config.path_list_destination_files.to_path_buf_current_os(), "").unwrap();
+        Box::leak(Box::new(app_config))
+    }
+
+    #[test]
+    fn disks_with_lists_in_one_folder_keep_their_own_plan_header() {
+        let (ui_tx, _ui_rx) = std::sync::mpsc::channel();
+        let path_folder_lists = new_test_folder("mirror_set_lists");
+        let base_path_1 = new_test_folder("mirror_set_disk_1");
+        let base_path_2 = new_test_folder("mirror_set_disk_2");
+        let include_roots = ["/".to_string()];
+        initialize_disk_identity(ui_tx.clone(), &base_path_1, "dbid:1", &include_roots).unwrap();
+        initialize_disk_identity(ui_tx.clone(), &base_path_2, "dbid:1", &include_roots).unwrap();
+        let disk_1 = disk_config(&path_folder_lists, "disk_1", &base_path_1);
+        let disk_2 = disk_config(&path_folder_lists, "disk_2", &base_path_2);
+        std::fs::write(
+            disk_1.path_list_source_files.to_path_buf_current_os(),
+            "/a.txt\t2024-01-01T00:00:00Z\t1\tcontent_hash",
+        )
+        .unwrap();
+
+        // a disk with the lists of another disk is refused
+        let disk_1_again = disk_config(&path_folder_lists, "disk_1", &base_path_1);
+        assert!(MirrorSet::new(vec![disk_1, disk_1_again]).is_err());
+
+        let mirror_set = MirrorSet::new(vec![disk_1, disk_2]).unwrap();
+        let compare_options = CompareOptions {
+            timestamp_tolerance: Some(chrono::Duration::seconds(2)),
+            ..Default::default()
+        };
+        compare_mirror_set(ui_tx, &mirror_set, &compare_options, "dbid:1", &include_roots).unwrap();
+
+        // the compare of the second disk does not overwrite the plan header of the first disk
+        let file_list_for_download_1 = FileTxt::open_for_read(&disk_1.path_list_for_download).unwrap();
+        let file_list_for_download_2 = FileTxt::open_for_read(&disk_2.path_list_for_download).unwrap();
+        assert!(verify_plan_header(&base_path_1, &file_list_for_download_1).is_ok());
+        assert!(verify_plan_header(&base_path_2, &file_list_for_download_2).is_ok());
+    }
+}
@@ -0,0 +1,462 @@
+// plan_mod.rs
+
+//! Dry-run of the sync.
+//!
+//! It runs all the planning steps in memory: compare files, compare folders and the matching for move, rename and copy.
+//! It returns the plan with counts and byte totals, but it does not write any list, it does not move or trash any file
+//! and it does not save the content_hash cache.
+//! The calculated content_hashes are only in memory and will be calculated again in the real sync.
+//!
+//! The real compare writes the plan header `list_plan_header_{uuid}.csv` in the same folder as the plan lists.
+//! It contains the path and the sha256 of the source and destination lists, that the plan was computed from.
+//! The header contains also the uuid of the backup disk from the identity file `0_backup_identity.json`.
+//! The uuid is also in the file name, so the disks of a mirror set can keep their plan lists in one folder.
+//! The steps that change the disk verify the header and refuse to run on a stale or mismatched plan or on another disk.
+//! compare_folders writes its own header `list_plan_header_folders_{uuid}.csv` for the lists of folders and the file plan header.
+
+use crossplatform_path::CrossPathBuf;
+
+use crate::compare_mod::{compare_files_in_memory, compare_folders_in_memory, read_compared_local_paths, CompareOptions};
+use crate::content_hash_cache_mod::ContentHashCache;
+use crate::disk_identity_mod::{read_disk_identity, DISK_IDENTITY_FILE_NAME};
+use crate::error_mod::{Error, Result};
+use crate::local_disk_mod::{match_local_files_by_content_hash, match_local_files_by_hash, match_local_files_by_name, remove_moved_lines};
+use crate::utils_mod::println_to_ui_thread;
+use crate::FileTxt;
+
+/// Count and total bytes for one step of the sync.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlanStep {
+    pub count: usize,
+    pub bytes: u64,
+}
+
+impl PlanStep {
+    /// Count and sum the size from the third column of the lines.
+    fn from_lines(vec_lines: &[&str]) -> Self {
+        let bytes = vec_lines
+            .iter()
+            .map(|line| line.split("\t").nth(2).unwrap_or_default().parse::<u64>().unwrap_or_default())
+            .sum();
+        PlanStep {
+            count: vec_lines.len(),
+            bytes,
+        }
+    }
+}
+
+/// The plan of the sync returned by the dry-run.  \
+///
+/// The steps are in the same order as in the real sync.  \
+/// Folders and case renames have no bytes, only the count. Move folders have the bytes of all files inside.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SyncPlan {
+    pub case_rename_folders: PlanStep,
+    pub case_rename_files: PlanStep,
+    pub move_folders: PlanStep,
+    pub move_files: PlanStep,
+    pub rename_files: PlanStep,
+    pub copy_files: PlanStep,
+    pub trash_files: PlanStep,
+    pub trash_folders: PlanStep,
+    pub create_folders: PlanStep,
+    pub change_time_files: PlanStep,
+    pub download: PlanStep,
+}
+
+/// Dry-run of the sync. It returns the plan with counts and byte totals.  \
+///
+/// The lists source and destination must be already sorted, like for compare_files.  \
+/// If the timestamp tolerance is not configured, it is read from `0_backup_temp` or it is 2 seconds, if it was never detected.  \
+/// Nothing is written to the disk.
+pub fn plan_sync(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    app_config: &'static crate::AppConfig,
+    compare_options: &CompareOptions,
+) -> Result<SyncPlan> {
+    let base_path = FileTxt::open_for_read(&app_config.path_list_ext_disk_base_path)?.read_to_string()?;
+    let base_path = CrossPathBuf::new(&base_path)?;
+
+    let string_list_source_files = FileTxt::open_for_read(&app_config.path_list_source_files)?.read_to_string()?;
+    let vec_list_source_files: Vec<&str> = string_list_source_files.lines().collect();
+    let string_list_destination_files = FileTxt::open_for_read(&app_config.path_list_destination_files)?.read_to_string()?;
+    let vec_list_destination_files: Vec<&str> = string_list_destination_files.lines().collect();
+    let string_list_source_folders = FileTxt::open_for_read(&app_config.path_list_source_folders)?.read_to_string()?;
+    let vec_list_source_folders: Vec<&str> = string_list_source_folders.lines().collect();
+    let string_list_destination_folders = FileTxt::open_for_read(&app_config.path_list_destination_folders)?.read_to_string()?;
+    let vec_list_destination_folders: Vec<&str> = string_list_destination_folders.lines().collect();
+
+    let timestamp_tolerance = compare_options.timestamp_tolerance_read_only(&base_path)?;
+    // the cache and the local paths of the last compare are only read, never saved in dry-run
+    let mut content_hash_cache = ContentHashCache::load(&base_path)?;
+    let set_compared_local_paths = read_compared_local_paths(&base_path)?;
+
+    let compared_files = compare_files_in_memory(
+        &ui_tx,
+        &base_path,
+        compare_options,
+        timestamp_tolerance,
+        &mut content_hash_cache,
+        &set_compared_local_paths,
+        &vec_list_source_files,
+        &vec_list_destination_files,
+    )?;
+    let mut vec_list_for_download: Vec<&str> = compared_files.vec_for_download.iter().map(|line| line.as_str()).collect();
+    let mut vec_list_for_trash_files: Vec<&str> = compared_files.vec_for_trash.iter().map(|line| line.as_str()).collect();
+    let vec_list_for_change_time_files: Vec<&str> = compared_files.vec_for_change_time_files.iter().map(|line| line.as_str()).collect();
+
+    // virtual move: the matched lines are removed from both lists, like in move_local_files
+    let vec_pairs = match_local_files_by_name(
+        &base_path,
+        &mut content_hash_cache,
+        &vec_list_for_trash_files,
+        &vec_list_for_download,
+        timestamp_tolerance,
+        compare_options.hash_only,
+    )?;
+    let move_files = PlanStep::from_lines(&vec_pairs.iter().map(|pair| vec_list_for_trash_files[pair.0]).collect::<Vec<&str>>());
+    remove_moved_lines(&mut vec_list_for_trash_files, &mut vec_list_for_download, &vec_pairs);
+
+    // virtual rename: the matched lines are removed from both lists, like in rename_local_files
+    let vec_pairs = match_local_files_by_hash(
+        &ui_tx,
+        &base_path,
+        &mut content_hash_cache,
+        &vec_list_for_trash_files,
+        &vec_list_for_download,
+        timestamp_tolerance,
+        compare_options.hash_only,
+    )?;
+    let rename_files = PlanStep::from_lines(&vec_pairs.iter().map(|pair| vec_list_for_trash_files[pair.0]).collect::<Vec<&str>>());
+    remove_moved_lines(&mut vec_list_for_trash_files, &mut vec_list_for_download, &vec_pairs);
+
+    // virtual copy of local duplicates: the matched lines are removed from list_for_download, like in copy_local_files
+    let vec_copies = match_local_files_by_content_hash(&base_path, &string_list_source_files, &vec_list_for_download)?;
+    let copy_files = PlanStep::from_lines(&vec_copies.iter().map(|copy| vec_list_for_download[copy.0]).collect::<Vec<&str>>());
+    let set_copied: std::collections::HashSet<usize> = vec_copies.iter().map(|copy| copy.0).collect();
+    let mut index = 0;
+    vec_list_for_download.retain(|_line| {
+        index += 1;
+        !set_copied.contains(&(index - 1))
+    });
+
+    let vec_list_for_move_folders: Vec<&str> = compared_files.vec_for_move_folders.iter().map(|line| line.as_str()).collect();
+    let (vec_for_trash_folders, vec_for_create_folders, vec_for_case_rename_folders) =
+        compare_folders_in_memory(&vec_list_source_folders, &vec_list_destination_folders, &vec_list_for_move_folders);
+
+    let move_folders = PlanStep {
+        count: compared_files.vec_for_move_folders.len(),
+        bytes: compared_files
+            .vec_for_move_folders
+            .iter()
+            .map(|line| line.split("\t").nth(3).unwrap_or_default().parse::<u64>().unwrap_or_default())
+            .sum(),
+    };
+
+    let sync_plan = SyncPlan {
+        move_folders,
+        case_rename_folders: PlanStep {
+            count: vec_for_case_rename_folders.len(),
+            bytes: 0,
+        },
+        case_rename_files: PlanStep {
+            count: compared_files.vec_for_case_rename_files.len(),
+            bytes: 0,
+        },
+        move_files,
+        rename_files,
+        copy_files,
+        trash_files: PlanStep::from_lines(&vec_list_for_trash_files),
+        trash_folders: PlanStep {
+            count: vec_for_trash_folders.len(),
+            bytes: 0,
+        },
+        create_folders: PlanStep {
+            count: vec_for_create_folders.len(),
+            bytes: 0,
+        },
+        change_time_files: PlanStep::from_lines(&vec_list_for_change_time_files),
+        download: PlanStep::from_lines(&vec_list_for_download),
+    };
+    println_to_ui_plan(&ui_tx, &sync_plan);
+    Ok(sync_plan)
+}
+
+/// Send the plan to the UI thread, one line for every step.
+fn println_to_ui_plan(ui_tx: &std::sync::mpsc::Sender<String>, sync_plan: &SyncPlan) {
+    for (name, plan_step) in [
+        ("case rename folders", sync_plan.case_rename_folders),
+        ("case rename files", sync_plan.case_rename_files),
+        ("move folders", sync_plan.move_folders),
+        ("move files", sync_plan.move_files),
+        ("rename files", sync_plan.rename_files),
+        ("copy files", sync_plan.copy_files),
+        ("trash files", sync_plan.trash_files),
+        ("trash folders", sync_plan.trash_folders),
+        ("create folders", sync_plan.create_folders),
+        ("change time files", sync_plan.change_time_files),
+        ("download", sync_plan.download),
+    ] {
+        println_to_ui_thread(ui_tx, format!("{name}: {}, {} bytes", plan_step.count, plan_step.bytes));
+    }
+}
+
+/// Start of the file name of the plan header, in the same folder as the plan lists.
+const PLAN_HEADER_FILE_PREFIX: &str = "list_plan_header";
+/// Start of the file name of the folder plan header, in the same folder as the folder plan lists.
+const FOLDER_PLAN_HEADER_FILE_PREFIX: &str = "list_plan_header_folders";
+
+/// Returns the path of the plan header of this disk next to the plan list.  \
+///
+/// The file name ends with the disk uuid. Other disks with plan lists in the same folder have their own header.  
+fn path_plan_header(path_list_plan: &CrossPathBuf, plan_header_file_prefix: &str, disk_uuid: &str) -> std::path::PathBuf {
+    path_list_plan
+        .to_path_buf_current_os()
+        .with_file_name(format!("{plan_header_file_prefix}_{disk_uuid}.csv"))
+}
+
+/// Returns the sha256 of the list content as hex string.
+fn sha256_of_list(string_list: &str) -> String {
+    use rsa::sha2::Digest;
+    format!("{:x}", rsa::sha2::Sha256::digest(string_list.as_bytes()))
+}
+
+/// Write the plan header next to the plan list.  \
+///
+/// The first line is `0_backup_identity.json\tuuid` with the uuid of the backup disk.  \
+/// Every other line is `path_of_list\tsha256_of_content` for the lists the plan was computed from.  \
+/// The header is replaced atomically like the plan lists.  
+pub(crate) fn write_plan_header(path_list_plan: &CrossPathBuf, disk_uuid: &str, lists: &[(&CrossPathBuf, &str)]) -> Result<()> {
+    write_plan_header_file(
+        &path_plan_header(path_list_plan, PLAN_HEADER_FILE_PREFIX, disk_uuid),
+        disk_uuid,
+        lists,
+    )
+}
+
+/// Write the folder plan header next to the folder plan list.  \
+///
+/// The folder plan is computed from the lists of folders and from list_for_move_folders of the file plan.
+/// The file plan header is added as one more list, so a new compare_files makes the folder plan stale.
+/// list_for_move_folders itself is not in the header, because move_local_folders empties it before trash_folders and create_folders.  
+pub(crate) fn write_folder_plan_header(
+    path_list_folder_plan: &CrossPathBuf,
+    path_list_plan: &CrossPathBuf,
+    disk_uuid: &str,
+    lists: &[(&CrossPathBuf, &str)],
+) -> Result<()> {
+    let path_header_files = path_plan_header(path_list_plan, PLAN_HEADER_FILE_PREFIX, disk_uuid);
+    let path_header_files = CrossPathBuf::new(&path_header_files.to_string_lossy())?;
+    let string_header_files = if path_header_files.exists() {
+        path_header_files.read_to_string()?
+    } else {
+        String::new()
+    };
+    let mut vec_lists = lists.to_vec();
+    vec_lists.push((&path_header_files, &string_header_files));
+    write_plan_header_file(
+        &path_plan_header(path_list_folder_plan, FOLDER_PLAN_HEADER_FILE_PREFIX, disk_uuid),
+        disk_uuid,
+        &vec_lists,
+    )
+}
+
+/// Write the header lines atomically with a temp file and rename.  
+fn write_plan_header_file(path_header: &std::path::Path, disk_uuid: &str, lists: &[(&CrossPathBuf, &str)]) -> Result<()> {
+    let mut vec_header = vec![format!("{DISK_IDENTITY_FILE_NAME}\t{disk_uuid}")];
+    vec_header.extend(
+        lists
+            .iter()
+            .map(|(path_list, string_list)| format!("{}\t{}", path_list.as_str(), sha256_of_list(string_list))),
+    );
+    let string_header = vec_header.join("\n");
+    let mut path_temp = path_header.to_path_buf().into_os_string();
+    path_temp.push(".tmp");
+    std::fs::write(&path_temp, string_header)?;
+    std::fs::rename(&path_temp, path_header)?;
+    Ok(())
+}
+
+/// Verify that the plan was computed from the actual source and destination lists for this backup disk.  \
+///
+/// It returns an error if the plan header does not exist or if any list has changed after compare.  \
+/// Then compare must run again, before the steps can continue.  \
+/// It returns an error also if the disk identity is missing or it is not the disk the plan was computed for.  
+pub fn verify_plan_header(ext_disk_base_path: &CrossPathBuf, file_list_plan: &FileTxt) -> Result<()> {
+    verify_plan_header_file(ext_disk_base_path, file_list_plan, PLAN_HEADER_FILE_PREFIX)
+}
+
+/// Verify that the folder plan was computed from the actual lists of folders and the actual file plan for this backup disk.  \
+///
+/// It returns an error if the folder plan header does not exist, if any list of folders has changed
+/// or if compare_files ran again after compare_folders. Then compare_folders must run again.  
+pub fn verify_folder_plan_header(ext_disk_base_path: &CrossPathBuf, file_list_folder_plan: &FileTxt) -> Result<()> {
+    verify_plan_header_file(ext_disk_base_path, file_list_folder_plan, FOLDER_PLAN_HEADER_FILE_PREFIX)
+}
+
+/// Verify the disk uuid and the sha256 of every list in the header.  
+fn verify_plan_header_file(ext_disk_base_path: &CrossPathBuf, file_list_plan: &FileTxt, plan_header_file_prefix: &str) -> Result<()> {
+    let disk_identity = read_disk_identity(ext_disk_base_path)?;
+    let path_header = path_plan_header(file_list_plan.file_path(), plan_header_file_prefix, &disk_identity.uuid);
+    if !path_header.exists() {
+        return Err(Error::ErrorFromString(format!(
+            "The plan header {} does not exist for the backup disk {ext_disk_base_path}. Run compare before {}.",
+            path_header.to_string_lossy(),
+            file_list_plan.file_name()?
+        )));
+    }
+    let mut is_disk_verified = false;
+    let string_header = std::fs::read_to_string(&path_header)?;
+    for line in string_header.lines() {
+        let (path_list, sha256_in_header) = line
+            .split_once("\t")
+            .ok_or(Error::ErrorFromStr("the line in the plan header must have 2 columns"))?;
+        if path_list == DISK_IDENTITY_FILE_NAME {
+            if sha256_in_header != disk_identity.uuid {
+                return Err(Error::ErrorFromString(format!(
+                    "The plan {} was computed for another backup disk, not for {ext_disk_base_path}. Run compare again.",
+                    file_list_plan.file_name()?
+                )));
+            }
+            is_disk_verified = true;
+            continue;
+        }
+        let path_list = CrossPathBuf::new(path_list)?;
+        let string_list = if path_list.exists() {
+            path_list.read_to_string()?
+        } else {
+            String::new()
+        };
+        if sha256_of_list(&string_list) != sha256_in_header {
+            return Err(Error::ErrorFromString(format!(
+                "The plan {} is stale, because {} has changed after compare. Run compare again.",
+                file_list_plan.file_name()?,
+                path_list
+            )));
+        }
+    }
+    if !is_disk_verified {
+        return Err(Error::ErrorFromString(format!(
+            "The plan header of {} has no disk identity. Run compare again.",
+            file_list_plan.file_name()?
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_identity_mod::initialize_disk_identity;
+    use crate::utils_mod::new_test_folder;
+
+    #[test]
+    fn plan_for_another_disk_is_refused() {
+        let (ui_tx, _ui_rx) = std::sync::mpsc::channel();
+        let base_path_1 = new_test_folder("plan_header_disk_1");
+        let base_path_2 = new_test_folder("plan_header_disk_2");
+        initialize_disk_identity(ui_tx.clone(), &base_path_1, "dbid:1", &["/".to_string()]).unwrap();
+        let disk_identity_2 = initialize_disk_identity(ui_tx, &base_path_2, "dbid:1", &["/".to_string()]).unwrap();
+
+        let path_list_source = base_path_1.join_relative("list_source_files.csv").unwrap();
+        std::fs::write(path_list_source.to_path_buf_current_os(), "/a.txt\t2024-01-01T00:00:00Z\t1").unwrap();
+        let path_list_plan = base_path_1.join_relative("list_for_download.csv").unwrap();
+        let file_list_plan = FileTxt::open_for_read_and_write(&path_list_plan).unwrap();
+        write_plan_header(
+            &path_list_plan,
+            &disk_identity_2.uuid,
+            &[(&path_list_source, "/a.txt\t2024-01-01T00:00:00Z\t1")],
+        )
+        .unwrap();
+
+        assert!(verify_plan_header(&base_path_2, &file_list_plan).is_ok());
+        assert!(verify_plan_header(&base_path_1, &file_list_plan).is_err());
+        // a changed source list makes the plan stale
+        std::fs::write(path_list_source.to_path_buf_current_os(), "/b.txt\t2024-01-01T00:00:00Z\t1").unwrap();
+        assert!(verify_plan_header(&base_path_2, &file_list_plan).is_err());
+    }
+
+    #[test]
+    fn stale_folder_plan_is_refused() {
+        let (ui_tx, _ui_rx) = std::sync::mpsc::channel();
+        let base_path = new_test_folder("folder_plan_header");
+        let disk_identity = initialize_disk_identity(ui_tx, &base_path, "dbid:1", &["/".to_string()]).unwrap();
+        let path_list_source_files = base_path.join_relative("list_source_files.csv").unwrap();
+        std::fs::write(path_list_source_files.to_path_buf_current_os(), "/a.txt\t2024-01-01T00:00:00Z\t1").unwrap();
+        let path_list_source_folders = base_path.join_relative("list_source_folders.csv").unwrap();
+        std::fs::write(path_list_source_folders.to_path_buf_current_os(), "/folder").unwrap();
+        let path_list_for_move_folders = base_path.join_relative("list_for_move_folders.csv").unwrap();
+        let path_list_for_trash_folders = base_path.join_relative("list_for_trash_folders.csv").unwrap();
+        let file_list_for_trash_folders = FileTxt::open_for_read_and_write(&path_list_for_trash_folders).unwrap();
+
+        write_plan_header(
+            &path_list_for_move_folders,
+            &disk_identity.uuid,
+            &[(&path_list_source_files, "/a.txt\t2024-01-01T00:00:00Z\t1")],
+        )
+        .unwrap();
+        // the file plan header is not enough for the folder steps
+        assert!(verify_folder_plan_header(&base_path, &file_list_for_trash_folders).is_err());
+        write_folder_plan_header(
+            &path_list_for_trash_folders,
+            &path_list_for_move_folders,
+            &disk_identity.uuid,
+            &[(&path_list_source_folders, "/folder")],
+        )
+        .unwrap();
+        assert!(verify_folder_plan_header(&base_path, &file_list_for_trash_folders).is_ok());
+
+        // a new compare_files makes the folder plan stale
+        std::fs::write(path_list_source_files.to_path_buf_current_os(), "/b.txt\t2024-01-01T00:00:00Z\t1").unwrap();
+        write_plan_header(
+            &path_list_for_move_folders,
+            &disk_identity.uuid,
+            &[(&path_list_source_files, "/b.txt\t2024-01-01T00:00:00Z\t1")],
+        )
+        .unwrap();
+        assert!(verify_folder_plan_header(&base_path, &file_list_for_trash_folders).is_err());
+
+        // a changed list of folders makes the folder plan stale
+        write_folder_plan_header(
+            &path_list_for_trash_folders,
+            &path_list_for_move_folders,
+            &disk_identity.uuid,
+            &[(&path_list_source_folders, "/folder")],
+        )
+        .unwrap();
+        assert!(verify_folder_plan_header(&base_path, &file_list_for_trash_folders).is_ok());
+        std::fs::write(path_list_source_folders.to_path_buf_current_os(), "/folder\n/other").unwrap();
+        assert!(verify_folder_plan_header(&base_path, &file_list_for_trash_folders).is_err());
+    }
+
+    #[test]
+    fn plan_sync_copies_a_local_duplicate_instead_of_download() {
+        let (ui_tx, _ui_rx) = std::sync::mpsc::channel();
+        let base_path = new_test_folder("plan_sync_copy");
+        std::fs::write(base_path.join_relative("a.txt").unwrap().to_path_buf_current_os(), "same").unwrap();
+        let path_folder_lists = base_path.join_relative("0_backup_lists").unwrap();
+        path_folder_lists.create_dir_all().unwrap();
+        let app_config: &'static crate::AppConfig = Box::leak(Box::new(crate::AppConfig::new(&path_folder_lists).unwrap()));
+        for (path_list, string_list) in [
+            (&app_config.path_list_ext_disk_base_path, base_path.as_str()),
+            (
+                &app_config.path_list_source_files,
+                "/a.txt\t2024-01-01T00:00:00Z\t4\thash_same\n/b.txt\t2024-01-01T00:00:00Z\t4\thash_same\n/c.txt\t2024-01-01T00:00:00Z\t7\thash_other",
+            ),
+            (&app_config.path_list_destination_files, "/a.txt\t2024-01-01T00:00:00Z\t4"),
+            (&app_config.path_list_source_folders, ""),
+            (&app_config.path_list_destination_folders, ""),
+        ] {
+            std::fs::write(path_list.to_path_buf_current_os(), string_list).unwrap();
+        }
+        let compare_options = CompareOptions {
+            timestamp_tolerance: Some(chrono::Duration::seconds(2)),
+            ..Default::default()
+        };
+        let sync_plan = plan_sync(ui_tx, app_config, &compare_options).unwrap();
+        assert_eq!((sync_plan.copy_files.count, sync_plan.copy_files.bytes), (1, 4));
+        assert_eq!((sync_plan.download.count, sync_plan.download.bytes), (1, 7));
+        // the dry-run does not copy
+        assert!(!base_path.join_relative("b.txt").unwrap().exists());
+    }
+}
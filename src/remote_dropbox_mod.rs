@@ -7,11 +7,24 @@
 use crossplatform_path::CrossPathBuf;
 use secrecy::ExposeSecret;
 
+use crate::compare_mod::{count_by_reason, filesystem_timestamp_tolerance, REASON_COLUMN_FOR_DOWNLOAD};
 use crate::error_mod::Error;
 use crate::error_mod::Result;
+use crate::plan_mod::verify_plan_header;
 use crate::utils_mod::println_to_ui_thread_with_thread_name;
 use crate::FileTxt;
 
+/// Files bigger than this are downloaded in parallel byte ranges.
+const CHUNKED_DOWNLOAD_THRESHOLD: u64 = 256 * 1024 * 1024;
+/// Dropbox calculates the content_hash from blocks of 4 MiB.
+const DROPBOX_HASH_BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+/// The byte range for one thread must be a multiple of the hash block size.
+const CHUNK_SIZE: u64 = 16 * DROPBOX_HASH_BLOCK_SIZE;
+/// Number of threads that download byte ranges of the same file.
+const CHUNK_THREADS: usize = 4;
+/// Number of retries without progress before a byte range download fails.
+const CHUNK_MAX_RETRIES: u32 = 5;
+
 // type alias for better expressing coder intention,
 // but programmatically identical to the underlying type
 type FolderList = Vec<String>;
@@ -47,6 +60,15 @@ pub fn test_connection() -> Result<()> {
     Ok(())
 }
 
+/// Returns the account id of the authenticated Dropbox user, for the disk identity.  
+pub fn remote_account_id() -> Result<String> {
+    let token = get_authorization_token()?;
+    let client = dropbox_sdk::default_client::UserAuthDefaultClient::new(token);
+    let full_account =
+        (dropbox_sdk::users::get_current_account(&client)?).map_err(|_err| Error::ErrorFromStr("Error get_current_account"))?;
+    Ok(full_account.account_id)
+}
+
 /// Read encoded token (from env), decode and return the authorization token.  
 pub fn get_authorization_token() -> Result<dropbox_sdk::oauth2::Authorization> {
     let client_id = crate::dropbox_api_token_with_oauth2_mod::DROPBOX_API_CONFIG
@@ -320,23 +342,40 @@ pub fn download_one_file(
 
 /// Download files from list.  \
 ///
-/// It removes just_downloaded from list_for_download, so this function can be stopped and then called again.  
+/// It removes just_downloaded from list_for_download, so this function can be stopped and then called again.  \
+/// The just_downloaded records contain the path, rev and content_hash: `path\trev\tcontent_hash`.  \
+/// A line is removed from list_for_download only if the path and the content_hash are exactly equal.  \
+/// If the remote file changed after the list was made, the content_hash differs and the file will be downloaded again.  
 pub fn download_from_list(
     ui_tx: std::sync::mpsc::Sender<(String, ThreadName)>,
     ext_disk_base_path: &CrossPathBuf,
     file_list_for_download: &mut FileTxt,
     file_list_just_downloaded: &mut FileTxt,
 ) -> Result<()> {
+    verify_plan_header(ext_disk_base_path, file_list_for_download)?;
     let list_for_download = file_list_for_download.read_to_string()?;
     let mut vec_list_for_download: Vec<&str> = list_for_download.lines().collect();
 
     //remove list_just_downloaded from list_for_download
     let list_just_downloaded = file_list_just_downloaded.read_to_string()?;
     if !list_just_downloaded.is_empty() {
-        let vec_list_just_downloaded: Vec<&str> = list_just_downloaded.lines().collect();
-        for just_downloaded in vec_list_just_downloaded.iter() {
-            vec_list_for_download.retain(|line| !line.starts_with(just_downloaded))
-        }
+        // a set of (path, content_hash) for exact match
+        let set_just_downloaded: std::collections::HashSet<(&str, &str)> = list_just_downloaded
+            .lines()
+            .filter_map(|line| {
+                let vec_line: Vec<&str> = line.split("\t").collect();
+                if vec_line.len() >= 3 {
+                    Some((vec_line[0], vec_line[2]))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        vec_list_for_download.retain(|line| {
+            let vec_line: Vec<&str> = line.split("\t").collect();
+            let content_hash = vec_line.get(3).copied().unwrap_or_default();
+            !set_just_downloaded.contains(&(vec_line[0], content_hash))
+        });
         let string_for_download = vec_list_for_download.join("\n");
         file_list_for_download.empty()?;
         file_list_for_download.write_append_str(&string_for_download)?;
@@ -360,6 +399,31 @@ fn download_from_vec(
     let client_ref = &client;
     // channel for inter-thread communication to send messages that will be appended to files
     let (files_append_tx, files_append_rx) = std::sync::mpsc::channel();
+    // the previous versions of overwritten files are moved in this folder, one folder for every run, like the trash folder
+    let versions_folder_name = chrono::Local::now().format("versions_%Y-%m-%d_%H-%M-%S").to_string();
+    let versions_folder_name_ref = &versions_folder_name;
+    // the rounding of the modified datetime is checked with the same tolerance of the filesystem as in compare
+    let timestamp_tolerance = filesystem_timestamp_tolerance(ext_disk_base_path)?;
+    // the cache of local content_hash gets the just downloaded files, so the next compare does not need to calculate it
+    let mut content_hash_cache = crate::content_hash_cache_mod::ContentHashCache::load(ext_disk_base_path)?;
+    let content_hash_cache_ref = &mut content_hash_cache;
+    // the lines of downloaded files are collected for the report by reason
+    println_to_ui_thread_with_thread_name(
+        &ui_tx,
+        format!(
+            "for download by reason: {}",
+            count_by_reason(vec_list_for_download, REASON_COLUMN_FOR_DOWNLOAD)
+        ),
+        "R",
+    );
+    let line_by_path: std::collections::HashMap<&str, &str> = vec_list_for_download
+        .iter()
+        .map(|line| (line.split("\t").next().unwrap_or_default().trim_start_matches('/'), *line))
+        .collect();
+    let line_by_path_ref = &line_by_path;
+    let mut vec_downloaded_lines: Vec<&str> = vec![];
+    let vec_downloaded_lines_ref = &mut vec_downloaded_lines;
+    let ui_tx_report = ui_tx.clone();
     //8 threads to download in parallel
     let pool = rayon::ThreadPoolBuilder::new().num_threads(8).build()?;
     pool.scope(move |scoped| {
@@ -381,6 +445,8 @@ fn download_from_vec(
                     match download_internal(
                         ui_tx_clone,
                         ext_disk_base_path,
+                        versions_folder_name_ref,
+                        timestamp_tolerance,
                         client_ref,
                         thread_num as i32,
                         &path_to_download,
@@ -405,18 +471,40 @@ fn download_from_vec(
                 file_list_just_downloaded
                     .write_append_str(&format!("{just_downloaded}\n"))
                     .expect("Inside closure cannot use ?. Bug: file_list_just_downloaded must be writable.");
+                // path, rev, content_hash
+                let vec_just_downloaded: Vec<&str> = just_downloaded.split("\t").collect();
+                if let Err(err) =
+                    content_hash_cache_ref.insert_from_disk(ext_disk_base_path, vec_just_downloaded[0], vec_just_downloaded[2])
+                {
+                    println_to_ui_thread_with_thread_name(&ui_tx, format!("Error content_hash_cache {err}"), "R");
+                }
+                if let Some(line) = line_by_path_ref.get(vec_just_downloaded[0].trim_start_matches('/')) {
+                    vec_downloaded_lines_ref.push(*line);
+                }
             }
         }
         // endregion: Receiver reads all msgs from the queue
     });
+    content_hash_cache.save()?;
+    println_to_ui_thread_with_thread_name(
+        &ui_tx_report,
+        format!(
+            "downloaded by reason: {}",
+            count_by_reason(&vec_downloaded_lines, REASON_COLUMN_FOR_DOWNLOAD)
+        ),
+        "R",
+    );
 
     Ok(())
 }
 
 /// Download one file with client object dropbox_sdk::default_client::UserAuthDefaultClient.
+#[allow(clippy::too_many_arguments)]
 fn download_internal(
     ui_tx: std::sync::mpsc::Sender<(String, ThreadName)>,
     ext_disk_base_path: &CrossPathBuf,
+    versions_folder_name: &str,
+    timestamp_tolerance: chrono::Duration,
     client: &dropbox_sdk::default_client::UserAuthDefaultClient,
     thread_num: i32,
     path_to_download: &CrossPathBuf,
@@ -429,7 +517,8 @@ fn download_internal(
     local_path.create_dir_all_for_file()?;
     let modified_str;
     let metadata_size;
-    let mut just_downloaded = String::new();
+    let metadata_content_hash;
+    let metadata_rev;
 
     // get datetime from remote
 
@@ -439,6 +528,8 @@ fn download_internal(
         dropbox_sdk::files::Metadata::File(metadata) => {
             modified_str = metadata.client_modified;
             metadata_size = metadata.size;
+            metadata_content_hash = metadata.content_hash;
+            metadata_rev = metadata.rev;
         }
         _ => {
             return Err(Error::ErrorFromStr("This is not a file on Dropbox"));
@@ -446,102 +537,489 @@ fn download_internal(
     }
 
     let system_time_modified = humantime::parse_rfc3339(&modified_str)?;
-    // let modified = filetime::FileTime::from_system_time(system_time_modified);
 
     // files of size 0 cannot be downloaded. I will just create them empty, because download empty file causes error 416
     if metadata_size == 0 {
-        if local_path.exists() {
-            std::fs::remove_file(local_path.to_path_buf_current_os())?;
-        }
+        // keep the previous version of the file
+        crate::local_disk_mod::move_to_versions(ext_disk_base_path, versions_folder_name, path_to_download.as_str())?;
         let _file = FileTxt::open_for_read_and_write(&local_path)?;
         println_to_ui_thread_with_thread_name(&ui_tx, local_path.to_string(), &thread_name);
     } else {
-        let mut bytes_out = 0u64;
-        let download_arg = dropbox_sdk::files::DownloadArg::new(path_to_download.to_string());
         let base_temp_path_to_download = ext_disk_base_path.join_relative("0_backup_temp")?.join_relative("download_temp")?;
         base_temp_path_to_download.create_dir_all()?;
         let unique_name = local_path.as_str().replace("/", "_");
         let temp_local_path = base_temp_path_to_download.join_relative(&unique_name)?;
-        let mut file = std::fs::OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(temp_local_path.to_path_buf_current_os())?;
-        file.set_modified(system_time_modified)?;
-
-        // I will download to a temp folder and then move the file to the right folder only when the download is complete.
-        'download: loop {
-            // TODO: I want to press a key to stop the downloading gracefully
-            // but this thread is NOT the ui thread
-            let result = dropbox_sdk::files::download(client, &download_arg, Some(bytes_out), None);
-            match result {
-                Ok(Ok(download_result)) => {
-                    let mut body = download_result.body.ok_or_else(|| Error::ErrorFromStr("Bug: body must exist"))?;
-                    loop {
-                        // limit read to 1 MiB per loop iteration so we can output progress
-                        // let mut input_chunk = (&mut body).take(1_048_576);
-                        use std::io::Read; //import trait
-                        let mut input_chunk = (&mut body).take(1_048_576);
-                        match std::io::copy(&mut input_chunk, &mut file) {
-                            Ok(0) => {
-                                break 'download;
-                            }
-                            Ok(len) => {
-                                bytes_out += len;
-                                if let Some(total) = download_result.content_length {
-                                    let string_to_print = format!(
-                                        "{:.01}% of {:.02} MB downloading {}",
-                                        bytes_out as f64 / total as f64 * 100.,
-                                        total as f64 / 1000000.,
-                                        crate::shorten_string(path_to_download.as_str(), 80)?
-                                    );
-                                    println_to_ui_thread_with_thread_name(&ui_tx, string_to_print, &thread_name);
-                                    just_downloaded = path_to_download.to_string();
-                                } else {
-                                    let string_to_print = format!(
-                                        "{} MB downloaded {}",
-                                        bytes_out as f64 / 1000000.,
-                                        crate::shorten_string(path_to_download.as_str(), 80)?
-                                    );
+        if metadata_size >= CHUNKED_DOWNLOAD_THRESHOLD {
+            // very large files are downloaded in parallel byte ranges and verified with the content_hash
+            let content_hash = metadata_content_hash
+                .as_deref()
+                .ok_or_else(|| Error::ErrorFromStr("metadata.content_hash None"))?;
+            download_chunked(
+                &ui_tx,
+                client,
+                &thread_name,
+                &path_to_download,
+                &temp_local_path,
+                metadata_size,
+                content_hash,
+            )?;
+        } else {
+            let mut bytes_out = 0u64;
+            let download_arg = dropbox_sdk::files::DownloadArg::new(path_to_download.to_string());
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(temp_local_path.to_path_buf_current_os())?;
+            let mut is_download_complete = false;
+
+            // I will download to a temp folder and then move the file to the right folder only when the download is complete.
+            'download: loop {
+                // TODO: I want to press a key to stop the downloading gracefully
+                // but this thread is NOT the ui thread
+                let result = dropbox_sdk::files::download(client, &download_arg, Some(bytes_out), None);
+                match result {
+                    Ok(Ok(download_result)) => {
+                        let mut body = download_result.body.ok_or_else(|| Error::ErrorFromStr("Bug: body must exist"))?;
+                        loop {
+                            // limit read to 1 MiB per loop iteration so we can output progress
+                            // let mut input_chunk = (&mut body).take(1_048_576);
+                            use std::io::Read; //import trait
+                            let mut input_chunk = (&mut body).take(1_048_576);
+                            match std::io::copy(&mut input_chunk, &mut file) {
+                                Ok(0) => {
+                                    is_download_complete = true;
+                                    break 'download;
+                                }
+                                Ok(len) => {
+                                    bytes_out += len;
+                                    if let Some(total) = download_result.content_length {
+                                        let string_to_print = format!(
+                                            "{:.01}% of {:.02} MB downloading {}",
+                                            bytes_out as f64 / total as f64 * 100.,
+                                            total as f64 / 1000000.,
+                                            crate::shorten_string(path_to_download.as_str(), 80)?
+                                        );
+                                        println_to_ui_thread_with_thread_name(&ui_tx, string_to_print, &thread_name);
+                                    } else {
+                                        let string_to_print = format!(
+                                            "{} MB downloaded {}",
+                                            bytes_out as f64 / 1000000.,
+                                            crate::shorten_string(path_to_download.as_str(), 80)?
+                                        );
+                                        println_to_ui_thread_with_thread_name(&ui_tx, string_to_print, &thread_name);
+                                    }
+                                }
+                                Err(e) => {
+                                    let string_to_print = format!("Read error: {}", e);
                                     println_to_ui_thread_with_thread_name(&ui_tx, string_to_print, &thread_name);
+                                    continue 'download; // do another request and resume
                                 }
                             }
-                            Err(e) => {
-                                let string_to_print = format!("Read error: {}", e);
-                                println_to_ui_thread_with_thread_name(&ui_tx, string_to_print, &thread_name);
-                                continue 'download; // do another request and resume
-                            }
                         }
                     }
+                    Ok(Err(download_error)) => {
+                        let string_to_print = format!("Download error: {}", download_error);
+                        println_to_ui_thread_with_thread_name(&ui_tx, string_to_print, &thread_name);
+                    }
+                    Err(request_error) => {
+                        let string_to_print = format!("Error: {}", request_error);
+                        println_to_ui_thread_with_thread_name(&ui_tx, string_to_print, &thread_name);
+                    }
                 }
-                Ok(Err(download_error)) => {
-                    let string_to_print = format!("Download error: {}", download_error);
-                    println_to_ui_thread_with_thread_name(&ui_tx, string_to_print, &thread_name);
-                }
-                Err(request_error) => {
-                    let string_to_print = format!("Error: {}", request_error);
-                    println_to_ui_thread_with_thread_name(&ui_tx, string_to_print, &thread_name);
-                }
+                break 'download;
+            }
+            // never move an incomplete file in place of the backup file
+            if !is_download_complete || bytes_out != metadata_size {
+                return Err(Error::ErrorFromString(format!(
+                    "{thread_name} Error download incomplete {bytes_out} of {metadata_size} bytes: {path_to_download}"
+                )));
             }
-            break 'download;
         }
-        /*         // change the datetime of the file
-        if let Err(err) = filetime::set_file_mtime(&temp_local_path, modified){
-            let string_to_print = format!("Error: {} {} {err}",temp_local_path.to_string_lossy(), modified.unix_seconds());
-            println_to_ui_thread_with_thread_name(&ui_tx, string_to_print, &thread_name);
-        } */
-
+        // keep the previous version of the file, before it is overwritten
+        crate::local_disk_mod::move_to_versions(ext_disk_base_path, versions_folder_name, path_to_download.as_str())?;
         // move the completed download file to his final folder
         std::fs::rename(temp_local_path.to_path_buf_current_os(), local_path.to_path_buf_current_os())?;
     }
+    // The modified datetime is changed only after the last write and the rename, because every write can reset it on many filesystems.
+    set_and_check_modified(&ui_tx, &thread_name, &local_path, system_time_modified, timestamp_tolerance)?;
     // Cannot change the LastWrite/modified time from the Linux container in WSL to external exFAT on Windows.
     // I will instead cross-compile to Windows and run the exe in Windows where it works much better with the external exFAT drive.
     // From this thread I have to send a message to the other thread to avoid multiple threads writing to the same file. That is a no-no.
     // I use the channel files_append_tx to send messages from many threads to just one receiver.
     // That receiver can append to files without worrying of other threads interfering.
     // So only a single thread can write to a file. That is then sure to be serial and never simultaneously (data race).
-
+    // The record is sent only now, when the file is safely in place. It has the same path as in list_for_download (without leading slash).
+    let just_downloaded = format!(
+        "{}\t{}\t{}",
+        path_to_download.as_str().trim_start_matches('/'),
+        metadata_rev,
+        metadata_content_hash.unwrap_or_default()
+    );
     files_append_tx.send(just_downloaded)?;
 
     Ok(())
 }
+
+/// Set the modified datetime of the downloaded file and read it back to check it.  \
+///
+/// Some filesystems reject the modified datetime or round it (exFAT has 2 seconds resolution).  \
+/// A rounding within the timestamp tolerance of the filesystem is reported only as progress, because compare tolerates it.  \
+/// A rejected or a bigger difference is reported as error, because the next compare would find a different date.  
+fn set_and_check_modified(
+    ui_tx: &std::sync::mpsc::Sender<(String, ThreadName)>,
+    thread_name: &str,
+    local_path: &CrossPathBuf,
+    system_time_modified: std::time::SystemTime,
+    timestamp_tolerance: chrono::Duration,
+) -> Result<()> {
+    let modified = filetime::FileTime::from_system_time(system_time_modified);
+    if let Err(err) = filetime::set_file_mtime(local_path.to_path_buf_current_os(), modified) {
+        println_to_ui_thread_with_thread_name(ui_tx, format!("Error set modified rejected: {local_path} {err}"), thread_name);
+    }
+    let expected_modified: chrono::DateTime<chrono::Utc> = system_time_modified.into();
+    let local_modified: chrono::DateTime<chrono::Utc> = std::fs::metadata(local_path.to_path_buf_current_os())?.modified()?.into();
+    let difference = (local_modified - expected_modified).abs();
+    if difference > timestamp_tolerance {
+        println_to_ui_thread_with_thread_name(
+            ui_tx,
+            format!(
+                "Error modified is {} instead of {}: {local_path}",
+                local_modified.format("%Y-%m-%dT%TZ"),
+                expected_modified.format("%Y-%m-%dT%TZ")
+            ),
+            thread_name,
+        );
+    } else if difference > chrono::Duration::zero() {
+        println_to_ui_thread_with_thread_name(
+            ui_tx,
+            format!(
+                "modified rounded by {} ms: {}",
+                difference.num_milliseconds(),
+                crate::shorten_string(local_path.as_str(), 80)?
+            ),
+            thread_name,
+        );
+    }
+    Ok(())
+}
+
+/// Download a very large file in parallel byte ranges into a preallocated temp file.  \
+///
+/// The Dropbox content_hash is the sha256 of the concatenated sha256 of every 4 MiB block.  \
+/// Every byte range is a multiple of 4 MiB, so every thread calculates the hashes of its own blocks independently.  \
+/// In the end the block hashes are combined in order and compared with the remote content_hash.  
+fn download_chunked(
+    ui_tx: &std::sync::mpsc::Sender<(String, ThreadName)>,
+    client: &dropbox_sdk::default_client::UserAuthDefaultClient,
+    thread_name: &str,
+    path_to_download: &CrossPathBuf,
+    temp_local_path: &CrossPathBuf,
+    size: u64,
+    content_hash: &str,
+) -> Result<()> {
+    use rayon::prelude::*;
+    use rsa::sha2::Digest;
+
+    // preallocate the temp file, so every thread can write in his own byte range
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(temp_local_path.to_path_buf_current_os())?;
+    file.set_len(size)?;
+    drop(file);
+
+    let vec_ranges: Vec<(u64, u64)> = (0..size)
+        .step_by(CHUNK_SIZE as usize)
+        .map(|range_start| (range_start, (range_start + CHUNK_SIZE).min(size)))
+        .collect();
+    let bytes_out = std::sync::atomic::AtomicU64::new(0);
+    // this thread is already one of the download threads, the byte ranges need a separate small threadpool
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(CHUNK_THREADS).build()?;
+    let vec_results: Vec<Result<Vec<Vec<u8>>>> = pool.install(|| {
+        vec_ranges
+            .par_iter()
+            .map(|(range_start, range_end)| {
+                download_range(
+                    ui_tx,
+                    client,
+                    thread_name,
+                    path_to_download,
+                    temp_local_path,
+                    (*range_start, *range_end),
+                    size,
+                    &bytes_out,
+                )
+            })
+            .collect()
+    });
+
+    // combine the block hashes in order
+    let mut hasher = rsa::sha2::Sha256::new();
+    for result in vec_results {
+        for block_hash in result? {
+            hasher.update(&block_hash);
+        }
+    }
+    let local_content_hash = format!("{:x}", hasher.finalize());
+    if local_content_hash != content_hash {
+        std::fs::remove_file(temp_local_path.to_path_buf_current_os())?;
+        return Err(Error::ErrorFromString(format!(
+            "{thread_name} Error content_hash is not equal after chunked download: {path_to_download}"
+        )));
+    }
+    Ok(())
+}
+
+/// Download one byte range of a file and return the sha256 of every 4 MiB block inside the range.  \
+///
+/// After a read error it resumes with a new request from the last written byte.  
+#[allow(clippy::too_many_arguments)]
+fn download_range(
+    ui_tx: &std::sync::mpsc::Sender<(String, ThreadName)>,
+    client: &dropbox_sdk::default_client::UserAuthDefaultClient,
+    thread_name: &str,
+    path_to_download: &CrossPathBuf,
+    temp_local_path: &CrossPathBuf,
+    (range_start, range_end): (u64, u64),
+    size: u64,
+    bytes_out: &std::sync::atomic::AtomicU64,
+) -> Result<Vec<Vec<u8>>> {
+    use rsa::sha2::Digest;
+    use std::io::{Read, Seek, Write};
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(temp_local_path.to_path_buf_current_os())?;
+    let download_arg = dropbox_sdk::files::DownloadArg::new(path_to_download.to_string());
+    let mut block_hasher = rsa::sha2::Sha256::new();
+    let mut bytes_in_block = 0u64;
+    let mut vec_block_hashes = vec![];
+    let mut buffer = vec![0u8; 1_048_576];
+    let mut position = range_start;
+    let mut retries = 0;
+    let mut last_send_ms = std::time::Instant::now();
+    while position < range_end {
+        if retries > CHUNK_MAX_RETRIES {
+            return Err(Error::ErrorFromString(format!(
+                "{thread_name} Error too many retries for byte range {range_start}-{range_end}: {path_to_download}"
+            )));
+        }
+        let position_before_request = position;
+        // the end of the range in the request is inclusive
+        match dropbox_sdk::files::download(client, &download_arg, Some(position), Some(range_end - 1)) {
+            Ok(Ok(download_result)) => {
+                let mut body = download_result.body.ok_or_else(|| Error::ErrorFromStr("Bug: body must exist"))?;
+                file.seek(std::io::SeekFrom::Start(position))?;
+                loop {
+                    match body.read(&mut buffer) {
+                        Ok(0) => break,
+                        Ok(len) => {
+                            // never write outside of this byte range
+                            let len = len.min((range_end - position) as usize);
+                            file.write_all(&buffer[..len])?;
+                            // the hash is calculated for every 4 MiB block
+                            let mut slice = &buffer[..len];
+                            while !slice.is_empty() {
+                                let take = ((DROPBOX_HASH_BLOCK_SIZE - bytes_in_block) as usize).min(slice.len());
+                                block_hasher.update(&slice[..take]);
+                                bytes_in_block += take as u64;
+                                slice = &slice[take..];
+                                if bytes_in_block == DROPBOX_HASH_BLOCK_SIZE {
+                                    vec_block_hashes.push(block_hasher.finalize_reset().to_vec());
+                                    bytes_in_block = 0;
+                                }
+                            }
+                            position += len as u64;
+                            let all_bytes_out = bytes_out.fetch_add(len as u64, std::sync::atomic::Ordering::Relaxed) + len as u64;
+                            if last_send_ms.elapsed().as_millis() >= 1000 {
+                                let string_to_print = format!(
+                                    "{:.01}% of {:.02} MB downloading in chunks {}",
+                                    all_bytes_out as f64 / size as f64 * 100.,
+                                    size as f64 / 1000000.,
+                                    crate::shorten_string(path_to_download.as_str(), 80)?
+                                );
+                                println_to_ui_thread_with_thread_name(ui_tx, string_to_print, thread_name);
+                                last_send_ms = std::time::Instant::now();
+                            }
+                            if position >= range_end {
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            let string_to_print = format!("Read error: {}", e);
+                            println_to_ui_thread_with_thread_name(ui_tx, string_to_print, thread_name);
+                            // do another request and resume
+                            break;
+                        }
+                    }
+                }
+            }
+            Ok(Err(download_error)) => {
+                let string_to_print = format!("Download error: {}", download_error);
+                println_to_ui_thread_with_thread_name(ui_tx, string_to_print, thread_name);
+            }
+            Err(request_error) => {
+                let string_to_print = format!("Error: {}", request_error);
+                println_to_ui_thread_with_thread_name(ui_tx, string_to_print, thread_name);
+            }
+        }
+        if position == position_before_request {
+            retries += 1;
+        } else {
+            retries = 0;
+        }
+    }
+    // the last block of the file can be smaller than 4 MiB
+    if bytes_in_block > 0 {
+        vec_block_hashes.push(block_hasher.finalize().to_vec());
+    }
+    Ok(vec_block_hashes)
+}
+
+/// Download the revision history of files inside the selected folders.  \
+///
+/// Every element of revision_folders is a folder path and the maximum number of revisions for files inside this folder.  \
+/// For every file from list_source_files inside one of these folders, it requests the list of revisions from Dropbox.  \
+/// Every previous revision is stored beside the current file with the name `{file_name}.{server_modified}.{rev}.dbxrev`.  \
+/// The current rev is skipped, because it is the file itself.  \
+/// The files with exactly this name pattern are ignored by list_local, so they are never trashed on their own.  \
+/// When the file is moved, renamed or trashed, its revisions go with it.  \
+/// The folders are matched with path_key, like all other paths.  \
+/// Revisions that already exist on disk are not downloaded again, so this function can be stopped and then called again.  
+pub fn download_revisions(
+    ui_tx: std::sync::mpsc::Sender<(String, ThreadName)>,
+    ext_disk_base_path: &CrossPathBuf,
+    file_list_source_files: &FileTxt,
+    revision_folders: &[(String, u64)],
+) -> Result<()> {
+    let list_source_files = file_list_source_files.read_to_string()?;
+    // find the files inside the selected folders and the maximum number of revisions
+    let mut vec_for_revisions: Vec<(String, u64)> = vec![];
+    for line in list_source_files.lines() {
+        let path = line.split("\t").next().unwrap_or_default();
+        let path_key = crate::utils_mod::path_key(path);
+        let max_revisions = revision_folders.iter().find_map(|(folder, max_revisions)| {
+            let folder_key = crate::utils_mod::path_key(folder.trim_matches('/'));
+            if path_key.starts_with(&format!("{folder_key}/")) {
+                Some(*max_revisions)
+            } else {
+                None
+            }
+        });
+        if let Some(max_revisions) = max_revisions {
+            vec_for_revisions.push((path.to_string(), max_revisions));
+        }
+    }
+    println_to_ui_thread_with_thread_name(&ui_tx, format!("Files with revisions: {}", vec_for_revisions.len()), "R");
+
+    let token = get_authorization_token()?;
+    let client = dropbox_sdk::default_client::UserAuthDefaultClient::new(token);
+    // I have to create a reference before the move-closure. So the reference is moved to the closure and not the object.
+    let client_ref = &client;
+    //8 threads to download in parallel
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(8).build()?;
+    pool.scope(|scoped| {
+        for (path, max_revisions) in vec_for_revisions.iter() {
+            scoped.spawn({
+                // Prepare variables to be moved/captured to the closure. All is isolated in a block scope.
+                let ui_tx_clone = ui_tx.clone();
+                let ui_tx_move_to_closure_2 = ui_tx.clone();
+                // only the closure is actually spawned, because it is the return value of the block
+                move |_| {
+                    let thread_num = rayon::current_thread_index().expect("Inside closure cannot use ?. Bug: thread num must exist.");
+                    // catch propagated errors and communicate errors to user or developer
+                    // spawned closure cannot propagate error with ?
+                    match download_revisions_internal(ui_tx_clone, ext_disk_base_path, client_ref, thread_num as i32, path, *max_revisions)
+                    {
+                        Ok(()) => {}
+                        Err(err) => println_to_ui_thread_with_thread_name(
+                            &ui_tx_move_to_closure_2,
+                            format!("Error in thread {err}"),
+                            &format!("R{thread_num}"),
+                        ),
+                    }
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// Download the revisions of one file.  
+fn download_revisions_internal(
+    ui_tx: std::sync::mpsc::Sender<(String, ThreadName)>,
+    ext_disk_base_path: &CrossPathBuf,
+    client: &dropbox_sdk::default_client::UserAuthDefaultClient,
+    thread_num: i32,
+    path: &str,
+    max_revisions: u64,
+) -> Result<()> {
+    let thread_name = format!("R{thread_num}");
+    // the current rev is the file itself, it is not a revision to backup
+    let get_metadata_arg = dropbox_sdk::files::GetMetadataArg::new(format!("/{path}"));
+    let current_rev = match (dropbox_sdk::files::get_metadata(client, &get_metadata_arg)?)? {
+        dropbox_sdk::files::Metadata::File(metadata) => metadata.rev,
+        _ => return Err(Error::ErrorFromStr("This is not a file on Dropbox")),
+    };
+    // one more for the current rev. Dropbox returns max 100 revisions
+    let list_revisions_arg =
+        dropbox_sdk::files::ListRevisionsArg::new(format!("/{path}")).with_limit(max_revisions.saturating_add(1).clamp(1, 100));
+    let list_revisions_result = match dropbox_sdk::files::list_revisions(client, &list_revisions_arg) {
+        Ok(Ok(list_revisions_result)) => list_revisions_result,
+        Ok(Err(e)) => {
+            return Err(Error::ErrorFromString(format!(
+                "{thread_name} Error from files/list_revisions: {e}"
+            )))
+        }
+        Err(e) => return Err(Error::ErrorFromString(format!("{thread_name} Error API request: {e}"))),
+    };
+
+    let base_temp_path_to_download = ext_disk_base_path.join_relative("0_backup_temp")?.join_relative("download_temp")?;
+    base_temp_path_to_download.create_dir_all()?;
+    for revision in list_revisions_result.entries.iter() {
+        if revision.rev == current_rev {
+            continue;
+        }
+        let server_modified: chrono::DateTime<chrono::Utc> = chrono::DateTime::parse_from_rfc3339(&revision.server_modified)?.into();
+        let revision_path = format!("{path}.{}.{}.dbxrev", server_modified.format("%Y-%m-%d_%H-%M-%S"), revision.rev);
+        let local_revision_path = ext_disk_base_path.join_relative(&revision_path)?;
+        // the revisions never change, if it exists it is already downloaded
+        if local_revision_path.exists() {
+            continue;
+        }
+        println_to_ui_thread_with_thread_name(
+            &ui_tx,
+            format!("revision {}", crate::shorten_string(&revision_path, 80)?),
+            &thread_name,
+        );
+        local_revision_path.create_dir_all_for_file()?;
+        // files of size 0 cannot be downloaded. I will just create them empty, because download empty file causes error 416
+        if revision.size == 0 {
+            let _file = FileTxt::open_for_read_and_write(&local_revision_path)?;
+        } else {
+            // I will download to a temp folder and then move the file to the right folder only when the download is complete.
+            let unique_name = local_revision_path.as_str().replace("/", "_");
+            let temp_local_path = base_temp_path_to_download.join_relative(&unique_name)?;
+            let download_arg = dropbox_sdk::files::DownloadArg::new(format!("rev:{}", revision.rev));
+            match dropbox_sdk::files::download(client, &download_arg, None, None) {
+                Ok(Ok(download_result)) => {
+                    let mut body = download_result.body.ok_or_else(|| Error::ErrorFromStr("Bug: body must exist"))?;
+                    let mut file = std::fs::File::create(temp_local_path.to_path_buf_current_os())?;
+                    std::io::copy(&mut body, &mut file)?;
+                }
+                Ok(Err(download_error)) => return Err(Error::ErrorFromString(format!("{thread_name} Download error: {download_error}"))),
+                Err(request_error) => return Err(Error::ErrorFromString(format!("{thread_name} Error: {request_error}"))),
+            }
+            std::fs::rename(
+                temp_local_path.to_path_buf_current_os(),
+                local_revision_path.to_path_buf_current_os(),
+            )?;
+        }
+        let modified = filetime::FileTime::from_system_time(humantime::parse_rfc3339(&revision.client_modified)?);
+        filetime::set_file_mtime(local_revision_path.to_path_buf_current_os(), modified)?;
+    }
+    Ok(())
+}
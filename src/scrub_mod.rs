@@ -0,0 +1,269 @@
+// scrub_mod.rs
+
+//! Scrub the external disk to detect bit rot.
+//!
+//! The scrub reads every local file, calculates the Dropbox content_hash and compares it with list_source_files.  
+//! If size and modified are equal, but the content_hash is different, then the content on the disk is silently corrupted.  
+//! This is a separate category from the files that are just different from the remote, because these will be found by compare.  
+//! The scrub can be stopped and then called again. The already scrubbed paths are in list_scrub_progress.  
+//! It can be throttled to a maximum number of bytes per second, so the disk is usable also while scrubbing.
+
+use chrono::{DateTime, Utc};
+use crossplatform_path::CrossPathBuf;
+use dropbox_content_hasher::DropboxContentHasher;
+
+use crate::compare_mod::{filesystem_timestamp_tolerance, line_with_reason, REASON_CORRUPTED};
+use crate::content_hash_cache_mod::{local_modified_and_size, ContentHashCache};
+use crate::disk_identity_mod::read_disk_identity;
+use crate::error_mod::Result;
+use crate::utils_mod::{path_from_line, println_to_ui_thread};
+use crate::FileTxt;
+
+/// Scrub all the local files from list_source_files.  \
+///
+/// The silently corrupted files are appended to list_scrub_corrupted. The content_hash cache gets the content_hash that scrub calculated.  \
+/// If file_list_for_download is Some, all the files in list_scrub_corrupted are also appended to list_for_download, so they will be downloaded again.  \
+/// This includes the files found before the scrub was stopped. A file already in list_for_download is not appended again.  \
+/// With max_bytes_per_second the reading is throttled.  
+pub fn scrub(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    file_list_source_files: &FileTxt,
+    file_list_scrub_progress: &mut FileTxt,
+    file_list_scrub_corrupted: &mut FileTxt,
+    max_bytes_per_second: Option<u64>,
+    file_list_for_download: Option<&mut FileTxt>,
+) -> Result<()> {
+    // refuse to change a wrong disk or an empty mount point
+    read_disk_identity(ext_disk_base_path)?;
+    let list_source_files = file_list_source_files.read_to_string()?;
+    let list_scrub_progress = file_list_scrub_progress.read_to_string()?;
+    if list_scrub_progress.is_empty() {
+        // a new scrub starts from the beginning
+        file_list_scrub_corrupted.empty()?;
+    }
+    let set_scrubbed: std::collections::HashSet<&str> = list_scrub_progress.lines().collect();
+    println_to_ui_thread(&ui_tx, format!("already scrubbed: {}", set_scrubbed.len()));
+
+    let mut content_hash_cache = ContentHashCache::load(ext_disk_base_path)?;
+    // the same tolerance of the filesystem as in compare
+    let timestamp_tolerance = filesystem_timestamp_tolerance(ext_disk_base_path)?;
+    let mut count_ok = 0;
+    let mut count_missing = 0;
+    let mut count_size_differs = 0;
+    let mut count_modified_differs = 0;
+    let mut count_changed = 0;
+    let mut count_corrupted = 0;
+    let scrub_start = std::time::Instant::now();
+    let mut bytes_read = 0u64;
+    let mut last_send_ms = std::time::Instant::now();
+
+    for line_source in list_source_files.lines() {
+        let vec_line_source: Vec<&str> = line_source.split("\t").collect();
+        let string_path = vec_line_source[0];
+        if set_scrubbed.contains(string_path) {
+            continue;
+        }
+        let path_global = ext_disk_base_path.join_relative(string_path)?;
+        if !path_global.exists() {
+            count_missing += 1;
+        } else {
+            let metadata = std::fs::metadata(path_global.to_path_buf_current_os())?;
+            if metadata.len().to_string() != vec_line_source[2] {
+                count_size_differs += 1;
+            } else {
+                // the scrub must always read the content, the cache is not used here
+                let local_content_hash = format!("{:x}", DropboxContentHasher::hash_file(path_global.to_path_buf_current_os())?);
+                bytes_read += metadata.len();
+                let local_modified: DateTime<Utc> = metadata.modified()?.into();
+                let source_modified: DateTime<Utc> = DateTime::parse_from_rfc3339(vec_line_source[1])?.into();
+                let is_modified_equal = chrono::Duration::from(local_modified - source_modified).abs() <= timestamp_tolerance;
+                if local_content_hash == vec_line_source[3] {
+                    if is_modified_equal {
+                        count_ok += 1;
+                    } else {
+                        count_modified_differs += 1;
+                    }
+                    content_hash_cache.insert_from_disk(ext_disk_base_path, string_path, &local_content_hash)?;
+                } else if is_modified_equal {
+                    // equal size and modified, but different content: silent corruption
+                    println_to_ui_thread(&ui_tx, format!("corrupted: {string_path}"));
+                    file_list_scrub_corrupted.write_append_str(&format!("{line_source}\n"))?;
+                    count_corrupted += 1;
+                    // The old cached content_hash is still valid by size and modified, but it is wrong.
+                    // It is replaced and saved at once, so compare, move and copy by hash never trust it, even if the scrub is stopped.
+                    content_hash_cache.insert_from_disk(ext_disk_base_path, string_path, &local_content_hash)?;
+                    content_hash_cache.save()?;
+                } else {
+                    count_changed += 1;
+                }
+            }
+        }
+        // the progress is written after every file, so the scrub can be stopped and then called again
+        file_list_scrub_progress.write_append_str(&format!("{string_path}\n"))?;
+
+        // Every 1 second write the progress
+        if last_send_ms.elapsed().as_millis() >= 1000 {
+            println_to_ui_thread(
+                &ui_tx,
+                format!(
+                    "{:.02} MB scrubbed {}",
+                    bytes_read as f64 / 1000000.,
+                    crate::shorten_string(string_path, 80)?
+                ),
+            );
+            last_send_ms = std::time::Instant::now();
+        }
+        // throttle: wait until the average speed is below the maximum
+        if let Some(max_bytes_per_second) = max_bytes_per_second {
+            let expected_duration = std::time::Duration::from_secs_f64(bytes_read as f64 / max_bytes_per_second.max(1) as f64);
+            let elapsed = scrub_start.elapsed();
+            if expected_duration > elapsed {
+                std::thread::sleep(expected_duration - elapsed);
+            }
+        }
+    }
+    content_hash_cache.save()?;
+
+    if let Some(file_list_for_download) = file_list_for_download {
+        let list_for_download = file_list_for_download.read_to_string()?;
+        let mut set_for_download: std::collections::HashSet<&str> = list_for_download.lines().map(path_from_line).collect();
+        // all the corrupted files, also from before the scrub was stopped
+        let list_scrub_corrupted = file_list_scrub_corrupted.read_to_string()?;
+        let mut vec_requeue: Vec<String> = vec![];
+        for line in list_scrub_corrupted.lines() {
+            let string_path = path_from_line(line);
+            // insert returns false for a path already in list_for_download or already re-queued
+            if !set_for_download.insert(string_path) {
+                continue;
+            }
+            let local_content_hash = local_content_hash_from_cache(ext_disk_base_path, &content_hash_cache, string_path)?;
+            let remote_content_hash = line.split("\t").nth(3).unwrap_or_default();
+            vec_requeue.push(line_with_reason(line, REASON_CORRUPTED, &local_content_hash, remote_content_hash));
+        }
+        if !vec_requeue.is_empty() {
+            let separator = if list_for_download.is_empty() || list_for_download.ends_with('\n') {
+                ""
+            } else {
+                "\n"
+            };
+            file_list_for_download.write_append_str(&format!("{separator}{}", vec_requeue.join("\n")))?;
+        }
+        println_to_ui_thread(&ui_tx, format!("corrupted re-queued for download: {}", vec_requeue.len()));
+    }
+
+    println_to_ui_thread(&ui_tx, format!("scrub ok: {count_ok}"));
+    println_to_ui_thread(&ui_tx, format!("scrub missing: {count_missing}"));
+    println_to_ui_thread(&ui_tx, format!("scrub size differs: {count_size_differs}"));
+    println_to_ui_thread(&ui_tx, format!("scrub modified differs: {count_modified_differs}"));
+    println_to_ui_thread(&ui_tx, format!("scrub changed: {count_changed}"));
+    println_to_ui_thread(&ui_tx, format!("scrub corrupted: {count_corrupted}"));
+    // the scrub is complete, the next scrub starts from the beginning
+    file_list_scrub_progress.empty()?;
+    Ok(())
+}
+
+/// The content_hash that scrub calculated for the corrupted file, or empty if the file has changed since.  
+fn local_content_hash_from_cache(
+    ext_disk_base_path: &CrossPathBuf,
+    content_hash_cache: &ContentHashCache,
+    string_path: &str,
+) -> Result<String> {
+    let path_global = ext_disk_base_path.join_relative(string_path)?;
+    if !path_global.exists() {
+        return Ok(String::new());
+    }
+    let (modified, size) = local_modified_and_size(&path_global)?;
+    Ok(content_hash_cache
+        .get_valid(string_path, &modified, size)
+        .unwrap_or_default()
+        .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_identity_mod::initialize_disk_identity;
+    use crate::utils_mod::new_test_folder;
+
+    /// Write the file with a fixed modified and return its line for list_source_files with the content_hash of remote_content.
+    fn local_file_and_source_line(base_path: &CrossPathBuf, string_path: &str, local_content: &str, remote_content: &str) -> String {
+        let path_global = base_path.join_relative(string_path).unwrap();
+        std::fs::write(path_global.to_path_buf_current_os(), remote_content).unwrap();
+        let remote_content_hash = format!(
+            "{:x}",
+            DropboxContentHasher::hash_file(path_global.to_path_buf_current_os()).unwrap()
+        );
+        std::fs::write(path_global.to_path_buf_current_os(), local_content).unwrap();
+        filetime::set_file_mtime(
+            path_global.to_path_buf_current_os(),
+            filetime::FileTime::from_unix_time(1704067200, 0),
+        )
+        .unwrap();
+        format!(
+            "{string_path}\t2024-01-01T00:00:00Z\t{}\t{remote_content_hash}",
+            remote_content.len()
+        )
+    }
+
+    #[test]
+    fn resumed_scrub_requeues_the_corrupted_files_from_before_the_stop() {
+        let (ui_tx, _ui_rx) = std::sync::mpsc::channel();
+        let base_path = new_test_folder("scrub_resume");
+        initialize_disk_identity(ui_tx.clone(), &base_path, "dbid:1", &["/".to_string()]).unwrap();
+        let line_a = local_file_and_source_line(&base_path, "a.txt", "rotten", "stored");
+        let line_b = local_file_and_source_line(&base_path, "b.txt", "intact", "intact");
+        let line_c = local_file_and_source_line(&base_path, "c.txt", "broken", "sound!");
+        // the cache has the content_hash from before the corruption, it is still valid by size and modified
+        let mut content_hash_cache = ContentHashCache::load(&base_path).unwrap();
+        let remote_content_hash_c = line_c.split("\t").nth(3).unwrap().to_string();
+        content_hash_cache
+            .insert_from_disk(&base_path, "c.txt", &remote_content_hash_c)
+            .unwrap();
+        content_hash_cache.save().unwrap();
+
+        let path_list_source_files = base_path.join_relative("list_source_files.csv").unwrap();
+        std::fs::write(
+            path_list_source_files.to_path_buf_current_os(),
+            format!("{line_a}\n{line_b}\n{line_c}"),
+        )
+        .unwrap();
+        // the scrub was stopped after the corrupted a.txt
+        let path_list_scrub_progress = base_path.join_relative("list_scrub_progress.csv").unwrap();
+        std::fs::write(path_list_scrub_progress.to_path_buf_current_os(), "a.txt\n").unwrap();
+        let path_list_scrub_corrupted = base_path.join_relative("list_scrub_corrupted.csv").unwrap();
+        std::fs::write(path_list_scrub_corrupted.to_path_buf_current_os(), format!("{line_a}\n")).unwrap();
+        let path_list_for_download = base_path.join_relative("list_for_download.csv").unwrap();
+        std::fs::write(path_list_for_download.to_path_buf_current_os(), "").unwrap();
+
+        let file_list_source_files = FileTxt::open_for_read(&path_list_source_files).unwrap();
+        let mut file_list_scrub_progress = FileTxt::open_for_read_and_write(&path_list_scrub_progress).unwrap();
+        let mut file_list_scrub_corrupted = FileTxt::open_for_read_and_write(&path_list_scrub_corrupted).unwrap();
+        let mut file_list_for_download = FileTxt::open_for_read_and_write(&path_list_for_download).unwrap();
+        for _ in 0..2 {
+            scrub(
+                ui_tx.clone(),
+                &base_path,
+                &file_list_source_files,
+                &mut file_list_scrub_progress,
+                &mut file_list_scrub_corrupted,
+                None,
+                Some(&mut file_list_for_download),
+            )
+            .unwrap();
+        }
+
+        // the second scrub found the same files again, but they are re-queued only once
+        let list_for_download = path_list_for_download.read_to_string().unwrap();
+        let vec_paths: Vec<&str> = list_for_download.lines().map(path_from_line).collect();
+        assert_eq!(vec_paths, vec!["a.txt", "c.txt"]);
+        assert!(list_for_download
+            .lines()
+            .all(|line| line.contains(&format!("\t{REASON_CORRUPTED}\t"))));
+        // the cache has the content_hash of the corrupted content, not the old one
+        let content_hash_cache = ContentHashCache::load(&base_path).unwrap();
+        let (modified, size) = local_modified_and_size(&base_path.join_relative("c.txt").unwrap()).unwrap();
+        let cached_content_hash_c = content_hash_cache.get_valid("c.txt", &modified, size).unwrap();
+        assert_ne!(cached_content_hash_c, remote_content_hash_c);
+    }
+}
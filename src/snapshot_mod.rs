@@ -0,0 +1,197 @@
+// snapshot_mod.rs
+
+//! Point-in-time snapshots of the backup on the external disk `0_backup_snapshots/YYYY-MM-DD_HH-MM-SS`.
+//!
+//! A snapshot is a tree of hardlinks to the current backup files. It takes almost no space and almost no time.
+//! The folder starts with `0_backup` like the temp folder, so it is not in the local list and not compared with the remote.
+//! A hardlink shares the content and the metadata with the backup file. The next sync must never modify a backup file in place.
+//! Download, versions, move and rename replace the directory entry with rename. Change time and readonly break the hardlink first.
+
+use crossplatform_path::CrossPathBuf;
+
+use crate::disk_identity_mod::read_disk_identity;
+use crate::error_mod::{Error, Result};
+use crate::utils_mod::println_to_ui_thread;
+
+/// The folder of all snapshots, relative to the backup base path.
+pub(crate) const SNAPSHOTS_FOLDER_NAME: &str = "0_backup_snapshots";
+
+/// One snapshot folder.
+#[derive(Debug, Clone)]
+pub struct BackupSnapshot {
+    /// The folder name like `YYYY-MM-DD_HH-MM-SS`.
+    pub folder_name: String,
+    pub datetime: chrono::NaiveDateTime,
+    path: std::path::PathBuf,
+}
+
+/// Create a new snapshot as a hardlink tree of the current backup.  \
+///
+/// Call it only after a successful sync, so the snapshot is a consistent point in time.  \
+/// The temp folder and the snapshots folder are not part of the snapshot.  \
+/// It is refused on platforms other than Unix, because there the number of hardlinks is not known
+/// and the sync could not protect the snapshot from in-place changes of modified datetime and readonly.  \
+/// Returns the folder name of the new snapshot.
+pub fn create_snapshot(ui_tx: std::sync::mpsc::Sender<String>, ext_disk_base_path: &CrossPathBuf) -> Result<String> {
+    if cfg!(not(unix)) {
+        return Err(Error::ErrorFromStr(
+            "snapshots are supported only on Unix, because the hardlinks cannot be detected on this platform",
+        ));
+    }
+    let folder_name = chrono::Local::now().format("%Y-%m-%d_%H-%M-%S").to_string();
+    let base_snapshot_path = ext_disk_base_path
+        .join_relative(SNAPSHOTS_FOLDER_NAME)?
+        .join_relative(&folder_name)?;
+    if base_snapshot_path.exists() {
+        return Err(Error::ErrorFromString(format!("snapshot already exists: {base_snapshot_path}")));
+    }
+    base_snapshot_path.create_dir_all()?;
+
+    let base_path = ext_disk_base_path.to_path_buf_current_os();
+    let mut count_files = 0;
+    let mut last_send_ms = std::time::Instant::now();
+    let walkdir_iterator = walkdir::WalkDir::new(&base_path).into_iter().filter_entry(|entry| {
+        // only the special folders in the root are skipped
+        !(entry.depth() == 1 && entry.file_name().to_string_lossy().starts_with("0_backup_"))
+    });
+    for entry in walkdir_iterator {
+        let entry = entry?;
+        if entry.depth() == 0 {
+            continue;
+        }
+        let string_path = entry
+            .path()
+            .strip_prefix(&base_path)
+            .map_err(|_err| Error::ErrorFromStr("Bug: file must be inside base path"))?
+            .to_string_lossy()
+            .replace(r#"\"#, "/");
+        let path_in_snapshot = base_snapshot_path.join_relative(&string_path)?;
+        if entry.file_type().is_dir() {
+            path_in_snapshot.create_dir_all()?;
+        } else if entry.file_type().is_file() {
+            std::fs::hard_link(entry.path(), path_in_snapshot.to_path_buf_current_os())?;
+            count_files += 1;
+            // don't print every file, because print is slow. Check if 100ms passed
+            if last_send_ms.elapsed().as_millis() >= 100 {
+                println_to_ui_thread(&ui_tx, format!("{count_files}: {}", crate::shorten_string(&string_path, 80)?));
+                last_send_ms = std::time::Instant::now();
+            }
+        }
+    }
+    println_to_ui_thread(&ui_tx, format!("snapshot {folder_name} files: {count_files}"));
+    Ok(folder_name)
+}
+
+/// List all snapshots, the newest first.
+pub fn list_snapshots(ui_tx: std::sync::mpsc::Sender<String>, ext_disk_base_path: &CrossPathBuf) -> Result<Vec<BackupSnapshot>> {
+    let base_snapshots_path = ext_disk_base_path.join_relative(SNAPSHOTS_FOLDER_NAME)?;
+    let mut vec_snapshots = vec![];
+    if !base_snapshots_path.exists() {
+        return Ok(vec_snapshots);
+    }
+    for entry in std::fs::read_dir(base_snapshots_path.to_path_buf_current_os())? {
+        let entry = entry?;
+        let folder_name = entry.file_name().to_string_lossy().to_string();
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let Ok(datetime) = chrono::NaiveDateTime::parse_from_str(&folder_name, "%Y-%m-%d_%H-%M-%S") else {
+            println_to_ui_thread(&ui_tx, format!("Error snapshot folder name is not a date: {folder_name}"));
+            continue;
+        };
+        vec_snapshots.push(BackupSnapshot {
+            folder_name,
+            datetime,
+            path: entry.path(),
+        });
+    }
+    // newest first
+    vec_snapshots.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.datetime));
+    Ok(vec_snapshots)
+}
+
+/// Delete old snapshots.  \
+///
+/// A snapshot is kept if it is one of the last `keep_last` snapshots or if it is newer than `keep_days`.  \
+/// If both parameters are None, nothing is deleted. With dry_run it only sends the list of snapshots that would be deleted.  \
+/// The files are deleted only when the last hardlink to them is deleted.  \
+/// Returns the folder names of the deleted snapshots (or that would be deleted in dry-run).
+pub fn prune_snapshots(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    keep_last: Option<usize>,
+    keep_days: Option<i64>,
+    dry_run: bool,
+) -> Result<Vec<String>> {
+    // refuse to change a wrong disk or an empty mount point
+    read_disk_identity(ext_disk_base_path)?;
+    let mut vec_pruned = vec![];
+    if keep_last.is_none() && keep_days.is_none() {
+        println_to_ui_thread(&ui_tx, "no retention policy for snapshots".to_string());
+        return Ok(vec_pruned);
+    }
+    let now = chrono::Local::now().naive_local();
+    for (index, snapshot) in list_snapshots(ui_tx.clone(), ext_disk_base_path)?.iter().enumerate() {
+        let is_in_last = keep_last.is_some_and(|keep_last| index < keep_last);
+        let is_new = keep_days.is_some_and(|keep_days| now - snapshot.datetime < chrono::Duration::days(keep_days));
+        if is_in_last || is_new {
+            continue;
+        }
+        if dry_run {
+            println_to_ui_thread(&ui_tx, format!("would delete snapshot {}", snapshot.folder_name));
+        } else {
+            match std::fs::remove_dir_all(&snapshot.path) {
+                Ok(()) => println_to_ui_thread(&ui_tx, format!("deleted snapshot {}", snapshot.folder_name)),
+                Err(err) => {
+                    println_to_ui_thread(&ui_tx, format!("Error remove snapshot {}: {err}", snapshot.folder_name));
+                    continue;
+                }
+            }
+        }
+        vec_pruned.push(snapshot.folder_name.clone());
+    }
+    let verb = if dry_run { "would be deleted" } else { "deleted" };
+    println_to_ui_thread(&ui_tx, format!("snapshots {verb}: {}", vec_pruned.len()));
+    Ok(vec_pruned)
+}
+
+/// Replace the backup file with a copy of itself, if it is shared with another hardlink.  \
+///
+/// The other hardlink can be a snapshot or a duplicate made by copy_local_files with use_hardlink.  \
+/// After that the content, modified datetime and permissions can change without changing the other hardlink.  \
+/// Returns true if the hardlink was broken.
+pub(crate) fn break_hardlink(ext_disk_base_path: &CrossPathBuf, path_global: &CrossPathBuf) -> Result<bool> {
+    if !path_global.exists() {
+        return Ok(false);
+    }
+    let path = path_global.to_path_buf_current_os();
+    let metadata = std::fs::metadata(&path)?;
+    if !is_hardlinked(ext_disk_base_path, &metadata)? {
+        return Ok(false);
+    }
+    let mut path_temp = path.clone().into_os_string();
+    path_temp.push(".break_hardlink_temp");
+    // copy() copies also the permissions, but not the modified datetime
+    std::fs::copy(&path, &path_temp)?;
+    filetime::set_file_mtime(&path_temp, filetime::FileTime::from_last_modification_time(&metadata))?;
+    std::fs::rename(&path_temp, &path)?;
+    Ok(true)
+}
+
+/// The file has more than one hardlink.
+#[cfg(unix)]
+fn is_hardlinked(_ext_disk_base_path: &CrossPathBuf, metadata: &std::fs::Metadata) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(metadata.nlink() > 1)
+}
+
+/// The number of links is not available in stable Rust on this platform.  \
+///
+/// create_snapshot is refused here, but a disk can have snapshots made on Unix.
+/// Then every file is treated as shared, because the snapshots must stay intact.
+/// Without snapshots only the duplicates from copy_local_files with use_hardlink can be shared,
+/// and hard_link fails on file systems without hardlinks, then the file is copied.
+#[cfg(not(unix))]
+fn is_hardlinked(ext_disk_base_path: &CrossPathBuf, _metadata: &std::fs::Metadata) -> Result<bool> {
+    Ok(ext_disk_base_path.join_relative(SNAPSHOTS_FOLDER_NAME)?.exists())
+}
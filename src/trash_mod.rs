@@ -0,0 +1,407 @@
+// trash_mod.rs
+
+//! Maintenance of the trash folders `0_backup_temp/trash_YYYY-MM-DD_HH-MM-SS`.
+//!
+//! Every run of trash_files and trash_folders creates a new trash folder. Nothing deletes them automatically.
+//! The purge deletes whole trash runs by a retention policy. It has a dry-run that only lists what would be deleted.
+//! The trash snapshots can be listed and searched and a file or folder can be restored to its original relative path.
+
+use crossplatform_path::CrossPathBuf;
+
+use crate::disk_identity_mod::read_disk_identity;
+use crate::error_mod::{Error, Result};
+use crate::utils_mod::{path_key, println_to_ui_thread};
+
+/// Retention policy for the trash runs.  \
+///
+/// A trash run is deleted if any of the configured limits is exceeded. If all are None, nothing is deleted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrashPurgePolicy {
+    /// Keep the trash runs newer than this number of days.
+    pub keep_days: Option<i64>,
+    /// Keep the total size of trash under this number of bytes. The oldest runs are deleted first.
+    pub max_bytes: Option<u64>,
+    /// Keep only the last number of trash runs.
+    pub keep_last_runs: Option<usize>,
+}
+
+/// One trash folder from one run of trash_files or trash_folders.
+#[derive(Debug, Clone)]
+pub struct TrashSnapshot {
+    /// The folder name like `trash_YYYY-MM-DD_HH-MM-SS`.
+    pub folder_name: String,
+    pub datetime: chrono::NaiveDateTime,
+    /// Size of all files in bytes.
+    pub bytes: u64,
+    /// All files in the snapshot.
+    pub vec_files: Vec<TrashFile>,
+    path: std::path::PathBuf,
+}
+
+/// One file inside a trash snapshot.
+#[derive(Debug, Clone)]
+pub struct TrashFile {
+    /// The original path relative to the backup base path, with slash as separator.
+    pub string_path: String,
+    pub size: u64,
+}
+
+/// What to do when the restored path already exists in the backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreConflict {
+    /// Leave the existing file and the file in trash.
+    Skip,
+    /// Move the existing file to the versions folder and restore the file from trash.
+    Overwrite,
+    /// Restore the file from trash with a new name `name.restored_YYYY-MM-DD_HH-MM-SS.ext`.
+    Rename,
+}
+
+/// List all trash snapshots with their files, the newest first.
+pub fn list_trash(ui_tx: std::sync::mpsc::Sender<String>, ext_disk_base_path: &CrossPathBuf) -> Result<Vec<TrashSnapshot>> {
+    list_trash_runs(&ui_tx, ext_disk_base_path)
+}
+
+/// Returns all trash runs, the newest first.
+fn list_trash_runs(ui_tx: &std::sync::mpsc::Sender<String>, ext_disk_base_path: &CrossPathBuf) -> Result<Vec<TrashSnapshot>> {
+    let base_temp_path = ext_disk_base_path.join_relative("0_backup_temp")?;
+    let mut vec_trash_runs = vec![];
+    if !base_temp_path.exists() {
+        return Ok(vec_trash_runs);
+    }
+    for entry in std::fs::read_dir(base_temp_path.to_path_buf_current_os())? {
+        let entry = entry?;
+        let folder_name = entry.file_name().to_string_lossy().to_string();
+        if !entry.file_type()?.is_dir() || !folder_name.starts_with("trash_") {
+            continue;
+        }
+        let Ok(datetime) = chrono::NaiveDateTime::parse_from_str(&folder_name, "trash_%Y-%m-%d_%H-%M-%S") else {
+            println_to_ui_thread(ui_tx, format!("Error trash folder name is not a date: {folder_name}"));
+            continue;
+        };
+        let folder_path = entry.path();
+        let mut bytes = 0;
+        let mut vec_files = vec![];
+        for file_entry in walkdir::WalkDir::new(&folder_path) {
+            let file_entry = file_entry?;
+            if file_entry.file_type().is_file() {
+                let size = file_entry.metadata()?.len();
+                bytes += size;
+                let string_path = file_entry
+                    .path()
+                    .strip_prefix(&folder_path)
+                    .map_err(|_err| Error::ErrorFromStr("Bug: file must be inside trash folder"))?
+                    .to_string_lossy()
+                    .replace(r#"\"#, "/");
+                vec_files.push(TrashFile { string_path, size });
+            }
+        }
+        vec_files.sort_by_cached_key(|trash_file| path_key(&trash_file.string_path));
+        vec_trash_runs.push(TrashSnapshot {
+            folder_name,
+            datetime,
+            bytes,
+            vec_files,
+            path: folder_path,
+        });
+    }
+    // newest first
+    vec_trash_runs.sort_by_key(|trash_run| std::cmp::Reverse(trash_run.datetime));
+    Ok(vec_trash_runs)
+}
+
+/// Delete the old trash runs from `0_backup_temp` by the retention policy.  \
+///
+/// With dry_run nothing is deleted, it only sends the list of trash runs that would be deleted.  \
+/// Returns the folder names of the deleted trash runs (or that would be deleted in dry-run).
+pub fn purge_trash(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    trash_purge_policy: &TrashPurgePolicy,
+    dry_run: bool,
+) -> Result<Vec<String>> {
+    // refuse to change a wrong disk or an empty mount point
+    read_disk_identity(ext_disk_base_path)?;
+    let mut vec_purged = vec![];
+    if trash_purge_policy.keep_days.is_none() && trash_purge_policy.max_bytes.is_none() && trash_purge_policy.keep_last_runs.is_none() {
+        println_to_ui_thread(&ui_tx, "no retention policy for trash".to_string());
+        return Ok(vec_purged);
+    }
+    let now = chrono::Local::now().naive_local();
+    let vec_trash_runs = list_trash_runs(&ui_tx, ext_disk_base_path)?;
+    let vec_is_purged = trash_runs_to_purge(&vec_trash_runs, trash_purge_policy, now);
+
+    let mut bytes_kept = 0u64;
+    let mut bytes_purged = 0u64;
+    for (trash_run, is_purged) in vec_trash_runs.iter().zip(vec_is_purged) {
+        if !is_purged {
+            bytes_kept += trash_run.bytes;
+            continue;
+        }
+        if dry_run {
+            println_to_ui_thread(&ui_tx, format!("would delete {} {} bytes", trash_run.folder_name, trash_run.bytes));
+        } else {
+            match std::fs::remove_dir_all(&trash_run.path) {
+                Ok(()) => println_to_ui_thread(&ui_tx, format!("deleted {} {} bytes", trash_run.folder_name, trash_run.bytes)),
+                Err(err) => {
+                    println_to_ui_thread(&ui_tx, format!("Error remove trash {}: {err}", trash_run.folder_name));
+                    bytes_kept += trash_run.bytes;
+                    continue;
+                }
+            }
+        }
+        bytes_purged += trash_run.bytes;
+        vec_purged.push(trash_run.folder_name.clone());
+    }
+    let verb = if dry_run { "would be deleted" } else { "deleted" };
+    println_to_ui_thread(
+        &ui_tx,
+        format!(
+            "trash runs {verb}: {}, {bytes_purged} bytes, kept: {bytes_kept} bytes",
+            vec_purged.len()
+        ),
+    );
+    Ok(vec_purged)
+}
+
+/// For every trash run returns true if it must be purged by the retention policy. The runs are sorted newest first.  \
+///
+/// The size is summed from the newest. When a run does not fit under max_bytes, this run and all the older runs are purged,
+/// so the oldest runs are always purged first.
+fn trash_runs_to_purge(vec_trash_runs: &[TrashSnapshot], trash_purge_policy: &TrashPurgePolicy, now: chrono::NaiveDateTime) -> Vec<bool> {
+    let mut bytes_kept = 0u64;
+    let mut is_over_max_bytes = false;
+    let mut vec_is_purged = vec![];
+    for (index, trash_run) in vec_trash_runs.iter().enumerate() {
+        let is_too_many = trash_purge_policy
+            .keep_last_runs
+            .is_some_and(|keep_last_runs| index >= keep_last_runs);
+        let is_too_old = trash_purge_policy
+            .keep_days
+            .is_some_and(|keep_days| now - trash_run.datetime >= chrono::Duration::days(keep_days));
+        is_over_max_bytes = is_over_max_bytes
+            || trash_purge_policy
+                .max_bytes
+                .is_some_and(|max_bytes| bytes_kept + trash_run.bytes > max_bytes);
+        let is_purged = is_too_many || is_too_old || is_over_max_bytes;
+        if !is_purged {
+            bytes_kept += trash_run.bytes;
+        }
+        vec_is_purged.push(is_purged);
+    }
+    vec_is_purged
+}
+
+/// Search all trash snapshots by a pattern. Returns pairs of the snapshot folder name and the file.  \
+///
+/// The pattern can have the wildcards `*` and `?` and it is case insensitive.  \
+/// If the pattern contains a slash, it must match the whole relative path, else only the file name.  
+pub fn search_trash(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    pattern: &str,
+) -> Result<Vec<(String, TrashFile)>> {
+    let is_path_pattern = pattern.contains('/');
+    let pattern_key = path_key(pattern.trim_start_matches('/'));
+    let mut vec_found = vec![];
+    for trash_snapshot in list_trash_runs(&ui_tx, ext_disk_base_path)? {
+        for trash_file in trash_snapshot.vec_files.iter() {
+            let text = if is_path_pattern {
+                trash_file.string_path.as_str()
+            } else {
+                trash_file.string_path.rsplit('/').next().unwrap_or_default()
+            };
+            if is_wildcard_match(&pattern_key, &path_key(text)) {
+                vec_found.push((trash_snapshot.folder_name.clone(), trash_file.clone()));
+            }
+        }
+    }
+    println_to_ui_thread(&ui_tx, format!("found in trash: {}", vec_found.len()));
+    Ok(vec_found)
+}
+
+/// Wildcard match with `*` for any number of characters and `?` for one character.
+fn is_wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut index_pattern, mut index_text) = (0, 0);
+    // the position of the last star and the text position it matched
+    let mut last_star: Option<(usize, usize)> = None;
+    while index_text < text.len() {
+        if index_pattern < pattern.len() && (pattern[index_pattern] == '?' || pattern[index_pattern] == text[index_text]) {
+            index_pattern += 1;
+            index_text += 1;
+        } else if index_pattern < pattern.len() && pattern[index_pattern] == '*' {
+            last_star = Some((index_pattern, index_text));
+            index_pattern += 1;
+        } else if let Some((star_pattern, star_text)) = last_star {
+            // the star takes one more character
+            index_pattern = star_pattern + 1;
+            index_text = star_text + 1;
+            last_star = Some((star_pattern, star_text + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[index_pattern..].iter().all(|char| *char == '*')
+}
+
+/// Restore a file or a folder from a trash snapshot to its original relative path.  \
+///
+/// A folder is restored file by file, so the conflict strategy is used for every file.  \
+/// Returns the number of restored files.
+pub fn restore_from_trash(
+    ui_tx: std::sync::mpsc::Sender<String>,
+    ext_disk_base_path: &CrossPathBuf,
+    trash_folder_name: &str,
+    string_path: &str,
+    restore_conflict: RestoreConflict,
+) -> Result<usize> {
+    // refuse to change a wrong disk or an empty mount point
+    read_disk_identity(ext_disk_base_path)?;
+    let trash_datetime = chrono::NaiveDateTime::parse_from_str(trash_folder_name, "trash_%Y-%m-%d_%H-%M-%S")
+        .map_err(|_err| Error::ErrorFromString(format!("trash folder name is not a date: {trash_folder_name}")))?;
+    let base_trash_path = ext_disk_base_path
+        .join_relative("0_backup_temp")?
+        .join_relative(trash_folder_name)?;
+    let path_in_trash = base_trash_path.join_relative(string_path)?;
+    if !path_in_trash.exists() {
+        return Err(Error::ErrorFromString(format!("not found in trash: {path_in_trash}")));
+    }
+    let string_path = string_path.trim_start_matches('/');
+    let mut vec_files: Vec<String> = vec![];
+    if std::fs::metadata(path_in_trash.to_path_buf_current_os())?.is_dir() {
+        let folder_path = base_trash_path.to_path_buf_current_os();
+        for file_entry in walkdir::WalkDir::new(path_in_trash.to_path_buf_current_os()) {
+            let file_entry = file_entry?;
+            if file_entry.file_type().is_file() {
+                vec_files.push(
+                    file_entry
+                        .path()
+                        .strip_prefix(&folder_path)
+                        .map_err(|_err| Error::ErrorFromStr("Bug: file must be inside trash folder"))?
+                        .to_string_lossy()
+                        .replace(r#"\"#, "/"),
+                );
+            }
+        }
+    } else {
+        vec_files.push(string_path.to_string());
+    }
+
+    // one versions folder for this restore, like in download
+    let versions_folder_name = chrono::Local::now().format("versions_%Y-%m-%d_%H-%M-%S").to_string();
+    let mut count_restored = 0;
+    for string_file_path in vec_files.iter() {
+        let path_from = base_trash_path.join_relative(string_file_path)?;
+        let mut path_to = ext_disk_base_path.join_relative(string_file_path)?;
+        if path_to.exists() {
+            match restore_conflict {
+                RestoreConflict::Skip => {
+                    println_to_ui_thread(&ui_tx, format!("skip, already exists: {path_to}"));
+                    continue;
+                }
+                RestoreConflict::Overwrite => {
+                    crate::local_disk_mod::move_to_versions(ext_disk_base_path, &versions_folder_name, string_file_path)?;
+                }
+                RestoreConflict::Rename => {
+                    path_to = ext_disk_base_path.join_relative(&restored_name(string_file_path, &trash_datetime))?;
+                    if path_to.exists() {
+                        println_to_ui_thread(&ui_tx, format!("skip, already exists: {path_to}"));
+                        continue;
+                    }
+                }
+            }
+        }
+        println_to_ui_thread(&ui_tx, format!("restore {path_from}  ->  {path_to}"));
+        path_to.create_dir_all_for_file()?;
+        std::fs::rename(path_from.to_path_buf_current_os(), path_to.to_path_buf_current_os())?;
+        count_restored += 1;
+    }
+
+    // remove empty folders left in trash, the children first. remove_dir() returns error if the folder is not empty, that is ok.
+    if path_in_trash.exists() {
+        for entry in walkdir::WalkDir::new(path_in_trash.to_path_buf_current_os())
+            .contents_first(true)
+            .into_iter()
+            .flatten()
+        {
+            if entry.file_type().is_dir() {
+                let _ = std::fs::remove_dir(entry.path());
+            }
+        }
+    }
+    println_to_ui_thread(&ui_tx, format!("restored from trash: {count_restored}"));
+    Ok(count_restored)
+}
+
+/// The new name for restore with RestoreConflict::Rename: `name.restored_YYYY-MM-DD_HH-MM-SS.ext`.
+fn restored_name(string_path: &str, trash_datetime: &chrono::NaiveDateTime) -> String {
+    let suffix = trash_datetime.format("restored_%Y-%m-%d_%H-%M-%S").to_string();
+    let (parent, file_name) = string_path
+        .rsplit_once('/')
+        .map_or(("", string_path), |(parent, name)| (parent, name));
+    let new_name = match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => format!("{stem}.{suffix}.{extension}"),
+        _ => format!("{file_name}.{suffix}"),
+    };
+    if parent.is_empty() {
+        new_name
+    } else {
+        format!("{parent}/{new_name}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trash_run(days_ago: i64, bytes: u64, now: chrono::NaiveDateTime) -> TrashSnapshot {
+        let datetime = now - chrono::Duration::days(days_ago);
+        TrashSnapshot {
+            folder_name: datetime.format("trash_%Y-%m-%d_%H-%M-%S").to_string(),
+            datetime,
+            bytes,
+            vec_files: vec![],
+            path: std::path::PathBuf::new(),
+        }
+    }
+
+    #[test]
+    fn max_bytes_purges_the_oldest_runs_first() {
+        let now = chrono::NaiveDate::from_ymd_opt(2025, 6, 1).unwrap().and_hms_opt(12, 0, 0).unwrap();
+        // newest first: a big recent run must be kept, the older small runs are purged
+        let vec_trash_runs = vec![trash_run(1, 80, now), trash_run(2, 30, now), trash_run(3, 10, now)];
+        let policy = TrashPurgePolicy {
+            max_bytes: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(trash_runs_to_purge(&vec_trash_runs, &policy, now), vec![false, true, true]);
+    }
+
+    #[test]
+    fn keep_last_runs_and_keep_days() {
+        let now = chrono::NaiveDate::from_ymd_opt(2025, 6, 1).unwrap().and_hms_opt(12, 0, 0).unwrap();
+        let vec_trash_runs = vec![trash_run(1, 10, now), trash_run(5, 10, now), trash_run(40, 10, now)];
+        let policy = TrashPurgePolicy {
+            keep_last_runs: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(trash_runs_to_purge(&vec_trash_runs, &policy, now), vec![false, false, true]);
+        let policy = TrashPurgePolicy {
+            keep_days: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(trash_runs_to_purge(&vec_trash_runs, &policy, now), vec![false, true, true]);
+    }
+
+    #[test]
+    fn no_policy_purges_nothing() {
+        let now = chrono::NaiveDate::from_ymd_opt(2025, 6, 1).unwrap().and_hms_opt(12, 0, 0).unwrap();
+        let vec_trash_runs = vec![trash_run(1, 10, now), trash_run(500, 1_000_000, now)];
+        assert_eq!(
+            trash_runs_to_purge(&vec_trash_runs, &TrashPurgePolicy::default(), now),
+            vec![false, false]
+        );
+    }
+}
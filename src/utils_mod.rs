@@ -3,7 +3,6 @@
 //! A module with often used functions.
 
 use crate::error_mod::{Error, Result};
-use uncased::UncasedStr;
 
 // type alias for better expressing coder intention,
 // but programmatically identical to the underlying type
@@ -168,28 +167,77 @@ pub fn byte_pos_from_chars(text: &str, char_pos: usize) -> Result<usize> {
         .0)
 }
 
-/// Sort string lines case insensitive.  
+/// Key for path comparison: unicode normalization NFC and simple lowercase, like Dropbox path_lower.  \
+///
+/// macOS often creates file names in NFD, while other systems use NFC.  \
+/// The key is only for sorting and comparing. The original spelling is used for operations on disk.  \
+/// The case rule is simple lowercase (`to_lowercase`), deliberately not full Unicode case folding.  \
+/// Dropbox compares paths by path_lower, so "STRASSE", "Straße" and "STRAẞE" give "strasse", "straße" and "straße".  \
+/// Full case folding would make "straße" equal to "strasse", but on Dropbox they are 2 different files.  \
+/// Most paths are ASCII and they need only lowercase.  
+pub fn path_key(path: &str) -> String {
+    if path.is_ascii() {
+        path.to_ascii_lowercase()
+    } else {
+        use unicode_normalization::UnicodeNormalization;
+        path.nfc().collect::<String>().to_lowercase()
+    }
+}
+
+/// The paths are equal after unicode normalization NFC. The case is not ignored.  
+pub fn is_equal_normalized(path_1: &str, path_2: &str) -> bool {
+    use unicode_normalization::UnicodeNormalization;
+    path_1 == path_2 || path_1.nfc().eq(path_2.nfc())
+}
+
+/// Returns the path, that is the first column of the line.  
+pub fn path_from_line(line: &str) -> &str {
+    line.split("\t").next().unwrap_or(line)
+}
+
+/// Sort string lines by path, case insensitive and unicode normalized.  
 pub fn sort_string_lines(output_string: &str) -> String {
     let mut sorted_local: Vec<&str> = output_string.lines().collect();
 
     use rayon::prelude::*;
-    sorted_local.par_sort_unstable_by(|a, b| {
-        let aa: &UncasedStr = (*a).into();
-        let bb: &UncasedStr = (*b).into();
-        aa.cmp(bb)
-    });
+    sorted_local.par_sort_by_cached_key(|line| path_key(path_from_line(line)));
     // return
     sorted_local.join("\n")
 }
 
-/// Sort list case insensitive.  
+/// Sort list by path, case insensitive and unicode normalized.  
 pub fn sort_list(mut list: Vec<String>) -> String {
     use rayon::prelude::*;
-    list.par_sort_unstable_by(|a, b| {
-        let aa: &uncased::UncasedStr = a.as_str().into();
-        let bb: &uncased::UncasedStr = b.as_str().into();
-        aa.cmp(bb)
-    });
+    list.par_sort_by_cached_key(|line| path_key(path_from_line(line)));
     // join to string and write to file
     list.join("\n")
 }
+
+/// New empty folder in the OS temp folder for one test. The folder name must be unique across the tests.  
+#[cfg(test)]
+pub(crate) fn new_test_folder(name: &str) -> crossplatform_path::CrossPathBuf {
+    let path_folder = std::env::temp_dir().join(format!("dropbox_backup_test_{}_{name}", std::process::id()));
+    if path_folder.exists() {
+        std::fs::remove_dir_all(&path_folder).unwrap();
+    }
+    std::fs::create_dir_all(&path_folder).unwrap();
+    crossplatform_path::CrossPathBuf::new(&path_folder.to_string_lossy()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_key_lowercase_and_nfc() {
+        assert_eq!(path_key("Folder/File.TXT"), "folder/file.txt");
+        // NFD "e" with combining acute accent is equal to NFC "é"
+        assert_eq!(path_key("Cafe\u{301}.txt"), path_key("café.txt"));
+        assert_eq!(path_key("STRASSE"), "strasse");
+        assert_ne!(path_key("Straße"), path_key("strasse"));
+        // capital sharp s lowercases to ß, but ß is not folded to "ss"
+        assert_eq!(path_key("STRAẞE"), "straße");
+        assert_eq!(path_key("STRAẞE"), path_key("Straße"));
+        assert_ne!(path_key("ß"), path_key("ss"));
+    }
+}